@@ -0,0 +1,18 @@
+// Runtime linked into every kaleidoscope executable.
+//
+// Every function takes and returns doubles, so they can be declared with
+// `extern` in kaleidoscope, e.g. `extern printd(x)`.
+
+#include <stdio.h>
+
+/// print `x` followed by a newline
+double printd(double x) {
+    printf("%f\n", x);
+    return 0;
+}
+
+/// print `x` as an ascii char
+double putchard(double x) {
+    putchar((char)x);
+    return 0;
+}
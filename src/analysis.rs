@@ -1,16 +1,18 @@
+use std::{ffi::CString, ptr::null_mut};
+
 use llvm_sys::{
-    core::LLVMCreatePassManager,
-    prelude::LLVMPassManagerRef,
-    target_machine::{LLVMCreateTargetMachineOptions, LLVMTargetMachineOptionsRef},
-    transforms::pass_builder::*,
+    prelude::LLVMModuleRef, target_machine::LLVMTargetMachineRef, transforms::pass_builder::*,
 };
 
-use crate::bool_to_llvm;
+use crate::{bool_to_llvm, error::CompileError, get_error_msg, target::OptLevel, Result};
 
+/// Optimization pipeline of the new pass manager, run over a module by [`PassManager::run`]
 pub struct PassManager {
-    pass_manager: LLVMPassManagerRef,
+    /// in the syntax of `opt -passes`, e.g. `default<O2>`
+    passes: String,
+    /// `None` for a custom pipeline
+    opt_level: Option<OptLevel>,
     options: LLVMPassBuilderOptionsRef,
-    machine_options: LLVMTargetMachineOptionsRef,
 }
 
 macro_rules! impl_enable_for_builder_options {
@@ -28,16 +30,45 @@ macro_rules! impl_enable_for_builder_options {
 }
 
 impl PassManager {
+    /// `default<O2>`
     pub fn new() -> Self {
+        Self::with_opt_level(OptLevel::default())
+    }
+
+    /// `default<O0>` to `default<O3>`, with the loops and straight-line code vectorized
+    /// from `-O2` like clang does
+    pub fn with_opt_level(opt_level: OptLevel) -> Self {
+        let mut pass_manager = Self::with_passes(pipeline(opt_level));
+        pass_manager.opt_level = Some(opt_level);
+        let vectorize = matches!(opt_level, OptLevel::Default | OptLevel::Aggressive);
+        pass_manager
+            .loop_vectorization(vectorize)
+            .slp_vectorization(vectorize)
+            .loop_interleaving(vectorize);
+
+        pass_manager
+    }
+
+    /// Custom pipeline, e.g. `function(mem2reg,instcombine,gvn)`
+    pub fn with_passes<S: Into<String>>(passes: S) -> Self {
         unsafe {
             Self {
-                pass_manager: LLVMCreatePassManager(),
+                passes: passes.into(),
+                opt_level: None,
                 options: LLVMCreatePassBuilderOptions(),
-                machine_options: LLVMCreateTargetMachineOptions(),
             }
         }
     }
 
+    pub fn passes(&self) -> &str {
+        &self.passes
+    }
+
+    /// Level of a `default<On>` pipeline, `None` for a custom one
+    pub fn opt_level(&self) -> Option<OptLevel> {
+        self.opt_level
+    }
+
     impl_enable_for_builder_options!(
         debug_logging => LLVMPassBuilderOptionsSetDebugLogging,
         call_graph_profile => LLVMPassBuilderOptionsSetCallGraphProfile,
@@ -47,4 +78,56 @@ impl PassManager {
         loop_unrolling => LLVMPassBuilderOptionsSetLoopUnrolling,
         forget_all_scev_in_loop_unroll => LLVMPassBuilderOptionsSetForgetAllSCEVInLoopUnroll
     );
+
+    /// Run the pipeline over `module`, `machine` tells the passes about the target,
+    /// e.g. the vector width
+    pub(crate) fn run(
+        &self,
+        module: LLVMModuleRef,
+        machine: Option<LLVMTargetMachineRef>,
+    ) -> Result<()> {
+        let Ok(passes) = CString::new(self.passes.as_str()) else {
+            return Err(CompileError::RunPassesError(format!(
+                "invalid pipeline `{}`",
+                self.passes
+            ))
+            .into());
+        };
+
+        unsafe {
+            let error = LLVMRunPasses(
+                module,
+                passes.as_ptr(),
+                machine.unwrap_or(null_mut()),
+                self.options,
+            );
+            if error.is_null() {
+                Ok(())
+            } else {
+                Err(CompileError::RunPassesError(get_error_msg(error)).into())
+            }
+        }
+    }
+}
+
+impl Default for PassManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for PassManager {
+    fn drop(&mut self) {
+        unsafe { LLVMDisposePassBuilderOptions(self.options) }
+    }
+}
+
+/// `default<On>` pipeline of `opt_level`
+fn pipeline(opt_level: OptLevel) -> &'static str {
+    match opt_level {
+        OptLevel::None => "default<O0>",
+        OptLevel::Less => "default<O1>",
+        OptLevel::Default => "default<O2>",
+        OptLevel::Aggressive => "default<O3>",
+    }
 }
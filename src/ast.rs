@@ -1,44 +1,135 @@
-use llvm_sys::prelude::LLVMValueRef;
+use llvm_sys::{prelude::LLVMValueRef, LLVMRealPredicate};
 
 use crate::compile::Compiler;
 use crate::error::CompileError;
 use crate::Result;
-use crate::{error::ParserError, lex::Token};
+use crate::{
+    error::ParserError,
+    lex::{Span, Token},
+};
 
 pub trait Codegen {
     fn codegen(&self, compiler: &mut Compiler) -> Result<LLVMValueRef>;
 }
 
+/// An expression with its location in the source
 #[derive(Debug)]
 pub enum ExprAst {
-    Number(f64),
-    Variable(String),
-    Binary(Box<BinaryExprAst>),
-    Call(Box<CallExprAst>),
+    Number(f64, Span),
+    Variable(String, Span),
+    Binary(Box<BinaryExprAst>, Span),
+    Unary(Box<UnaryExprAst>, Span),
+    Call(Box<CallExprAst>, Span),
+    If(Box<IfExprAst>, Span),
+    For(Box<ForExprAst>, Span),
+    Var(Box<VarExprAst>, Span),
+    /// source which failed to parse, see [`crate::parser::Parser::parse_all`]
+    Error(Span),
 }
 
-impl Codegen for ExprAst {
-    fn codegen(&self, compiler: &mut Compiler) -> Result<LLVMValueRef> {
+impl ExprAst {
+    pub fn span(&self) -> Span {
         match self {
-            ExprAst::Number(f) => Ok(compiler.const_double(*f)),
-            ExprAst::Variable(name) => match compiler.variable(name) {
-                Some(val) => Ok(val),
-                None => Err(CompileError::UnknowVariableName(name.clone()).into()),
-            },
-            ExprAst::Binary(binary_expr_ast) => binary_expr_ast.codegen(compiler),
-            ExprAst::Call(call_expr_ast) => call_expr_ast.codegen(compiler),
+            ExprAst::Number(_, span)
+            | ExprAst::Variable(_, span)
+            | ExprAst::Binary(_, span)
+            | ExprAst::Unary(_, span)
+            | ExprAst::Call(_, span)
+            | ExprAst::If(_, span)
+            | ExprAst::For(_, span)
+            | ExprAst::Var(_, span)
+            | ExprAst::Error(span) => *span,
         }
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+impl Codegen for ExprAst {
+    fn codegen(&self, compiler: &mut Compiler) -> Result<LLVMValueRef> {
+        let outer = compiler.set_location(self.span());
+
+        let value = match self {
+            ExprAst::Number(f, _) => Ok(compiler.const_double(*f)),
+            ExprAst::Variable(name, _) => compiler.create_load(name),
+            ExprAst::Binary(binary_expr_ast, _) => binary_expr_ast.codegen(compiler),
+            ExprAst::Unary(unary_expr_ast, _) => unary_expr_ast.codegen(compiler),
+            ExprAst::Call(call_expr_ast, _) => call_expr_ast.codegen(compiler),
+            ExprAst::If(if_expr_ast, _) => if_expr_ast.codegen(compiler),
+            ExprAst::For(for_expr_ast, _) => for_expr_ast.codegen(compiler),
+            ExprAst::Var(var_expr_ast, _) => var_expr_ast.codegen(compiler),
+            ExprAst::Error(span) => Err(CompileError::InvalidExpression(*span).into()),
+        };
+        compiler.set_location(outer);
+
+        value
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum OpSymbol {
     Add,
     Sub,
     Mul,
     Div,
+    /// `<`
     Less,
+    /// `>`
     Greater,
+    /// `<=`
+    LessEq,
+    /// `>=`
+    GreaterEq,
+    /// `==`
+    Equal,
+    /// `!=`
+    NotEq,
+    /// `&&`, the right side is only evaluated if the left side is true
+    And,
+    /// `||`, the right side is only evaluated if the left side is false
+    Or,
+    /// `x = expr`, the left side must be a variable
+    Assign,
+    /// user-defined operator, lowered to a call to `binary<op>`
+    Custom(char),
+}
+
+impl OpSymbol {
+    /// Predicate used to compare two doubles, `None` if `self` is not a comparison.
+    ///
+    /// All comparisons are ordered (false when either side is NaN) except `!=`,
+    /// which is unordered so that `x != x` holds exactly when `x` is NaN.
+    pub fn predicate(&self) -> Option<LLVMRealPredicate> {
+        match self {
+            OpSymbol::Less => Some(LLVMRealPredicate::LLVMRealOLT),
+            OpSymbol::Greater => Some(LLVMRealPredicate::LLVMRealOGT),
+            OpSymbol::LessEq => Some(LLVMRealPredicate::LLVMRealOLE),
+            OpSymbol::GreaterEq => Some(LLVMRealPredicate::LLVMRealOGE),
+            OpSymbol::Equal => Some(LLVMRealPredicate::LLVMRealOEQ),
+            OpSymbol::NotEq => Some(LLVMRealPredicate::LLVMRealUNE),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for OpSymbol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let symbol = match self {
+            OpSymbol::Add => "+",
+            OpSymbol::Sub => "-",
+            OpSymbol::Mul => "*",
+            OpSymbol::Div => "/",
+            OpSymbol::Less => "<",
+            OpSymbol::Greater => ">",
+            OpSymbol::LessEq => "<=",
+            OpSymbol::GreaterEq => ">=",
+            OpSymbol::Equal => "==",
+            OpSymbol::NotEq => "!=",
+            OpSymbol::And => "&&",
+            OpSymbol::Or => "||",
+            OpSymbol::Assign => "=",
+            OpSymbol::Custom(op) => return write!(f, "{op}"),
+        };
+        f.write_str(symbol)
+    }
 }
 
 impl TryFrom<Token> for OpSymbol {
@@ -50,7 +141,17 @@ impl TryFrom<Token> for OpSymbol {
             Token::Minus => Ok(Self::Sub),
             Token::Mul => Ok(Self::Mul),
             Token::Div => Ok(Self::Div),
-            _ => Err(ParserError::ParseOpSymbolError(value)),
+            Token::LeftAngle => Ok(Self::Less),
+            Token::RightAngle => Ok(Self::Greater),
+            Token::LessEq => Ok(Self::LessEq),
+            Token::GreaEq => Ok(Self::GreaterEq),
+            Token::Equal => Ok(Self::Equal),
+            Token::NotEq => Ok(Self::NotEq),
+            Token::And => Ok(Self::And),
+            Token::Or => Ok(Self::Or),
+            Token::Assign => Ok(Self::Assign),
+            Token::Operator(op) => Ok(Self::Custom(op)),
+            _ => Err(ParserError::ParseOpSymbolError(value, Span::default())),
         }
     }
 }
@@ -70,6 +171,18 @@ impl BinaryExprAst {
 
 impl Codegen for BinaryExprAst {
     fn codegen(&self, compiler: &mut Compiler) -> Result<LLVMValueRef> {
+        if let OpSymbol::Assign = self.op {
+            // the left side is a place, not a value
+            let ExprAst::Variable(name, _) = &self.lhs else {
+                return Err(CompileError::InvalidAssignTarget(compiler.span()).into());
+            };
+            return compiler.create_assign(name, &self.rhs);
+        }
+
+        if let OpSymbol::And | OpSymbol::Or = self.op {
+            return compiler.create_logical(&self.lhs, &self.rhs, self.op);
+        }
+
         let left = self.lhs.codegen(compiler)?;
         let right = self.rhs.codegen(compiler)?;
 
@@ -78,10 +191,68 @@ impl Codegen for BinaryExprAst {
             OpSymbol::Sub => "subtmp",
             OpSymbol::Mul => "multmp",
             OpSymbol::Div => "divtmp",
-            OpSymbol::Less | OpSymbol::Greater => "cmptmp",
+            OpSymbol::Less
+            | OpSymbol::Greater
+            | OpSymbol::LessEq
+            | OpSymbol::GreaterEq
+            | OpSymbol::Equal
+            | OpSymbol::NotEq => "cmptmp",
+            OpSymbol::Custom(_) => "binop",
+            OpSymbol::Assign | OpSymbol::And | OpSymbol::Or => {
+                unreachable!("non-eager operators are handled above")
+            }
+        };
+
+        compiler.create_binary(left, right, name, self.op)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UnaryOpSymbol {
+    /// `-x`
+    Neg,
+    /// `!x`, `1.0` if `x` is `0.0` else `0.0`
+    Not,
+    /// user-defined operator, lowered to a call to `unary<op>`
+    Custom(char),
+}
+
+impl TryFrom<Token> for UnaryOpSymbol {
+    type Error = ParserError;
+
+    fn try_from(value: Token) -> std::result::Result<Self, Self::Error> {
+        match value {
+            Token::Minus => Ok(Self::Neg),
+            Token::Exclamation => Ok(Self::Not),
+            Token::Operator(op) => Ok(Self::Custom(op)),
+            _ => Err(ParserError::ParseOpSymbolError(value, Span::default())),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct UnaryExprAst {
+    pub op: UnaryOpSymbol,
+    pub operand: ExprAst,
+}
+
+impl UnaryExprAst {
+    pub fn new(op: UnaryOpSymbol, operand: ExprAst) -> Self {
+        Self { op, operand }
+    }
+}
+
+impl Codegen for UnaryExprAst {
+    fn codegen(&self, compiler: &mut Compiler) -> Result<LLVMValueRef> {
+        let operand = self.operand.codegen(compiler)?;
+
+        let name = match self.op {
+            UnaryOpSymbol::Neg => "negtmp",
+            UnaryOpSymbol::Not => "nottmp",
+            UnaryOpSymbol::Custom(_) => "unop",
         };
 
-        Ok(compiler.create_binary(left, right, name, self.op))
+        compiler.create_unary(operand, name, self.op)
     }
 }
 
@@ -104,14 +275,137 @@ impl Codegen for CallExprAst {
 }
 
 #[derive(Debug)]
+pub struct IfExprAst {
+    pub cond: ExprAst,
+    pub then: ExprAst,
+    pub otherwise: ExprAst,
+}
+
+impl IfExprAst {
+    pub fn new(cond: ExprAst, then: ExprAst, otherwise: ExprAst) -> Self {
+        Self {
+            cond,
+            then,
+            otherwise,
+        }
+    }
+}
+
+impl Codegen for IfExprAst {
+    fn codegen(&self, compiler: &mut Compiler) -> Result<LLVMValueRef> {
+        compiler.create_if(self)
+    }
+}
+
+#[derive(Debug)]
+pub struct ForExprAst {
+    pub var: String,
+    pub start: ExprAst,
+    pub end: ExprAst,
+    /// `None` means step by `1.0`
+    pub step: Option<ExprAst>,
+    pub body: ExprAst,
+}
+
+impl ForExprAst {
+    pub fn new(
+        var: String,
+        start: ExprAst,
+        end: ExprAst,
+        step: Option<ExprAst>,
+        body: ExprAst,
+    ) -> Self {
+        Self {
+            var,
+            start,
+            end,
+            step,
+            body,
+        }
+    }
+}
+
+impl Codegen for ForExprAst {
+    fn codegen(&self, compiler: &mut Compiler) -> Result<LLVMValueRef> {
+        compiler.create_for(self)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PrototypeKind {
+    Function,
+    /// `binary<op> <precedence>`
+    Binary { op: char, precedence: i8 },
+    /// `unary<op>`
+    Unary { op: char },
+}
+
+#[derive(Debug)]
+pub struct VarExprAst {
+    /// variables and their initializers, `None` means `0.0`
+    pub vars: Vec<(String, Option<ExprAst>)>,
+    pub body: ExprAst,
+}
+
+impl VarExprAst {
+    pub fn new(vars: Vec<(String, Option<ExprAst>)>, body: ExprAst) -> Self {
+        Self { vars, body }
+    }
+}
+
+impl Codegen for VarExprAst {
+    fn codegen(&self, compiler: &mut Compiler) -> Result<LLVMValueRef> {
+        compiler.create_var(self)
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct PrototypeAst {
     pub name: String,
     pub args: Vec<String>,
+    pub kind: PrototypeKind,
+    /// `export def`, visible from a shared library
+    pub exported: bool,
+    pub span: Span,
+}
+
+impl PrototypeAst {
+    pub fn new(name: String, args: Vec<String>) -> Self {
+        Self {
+            name,
+            args,
+            kind: PrototypeKind::Function,
+            exported: false,
+            span: Span::default(),
+        }
+    }
+
+    /// Prototype of the function `binary<op>` which implements a user-defined binary operator
+    pub fn new_binary(op: char, precedence: i8, args: Vec<String>) -> Self {
+        Self {
+            name: format!("binary{op}"),
+            args,
+            kind: PrototypeKind::Binary { op, precedence },
+            exported: false,
+            span: Span::default(),
+        }
+    }
+
+    /// Prototype of the function `unary<op>` which implements a user-defined unary operator
+    pub fn new_unary(op: char, args: Vec<String>) -> Self {
+        Self {
+            name: format!("unary{op}"),
+            args,
+            kind: PrototypeKind::Unary { op },
+            exported: false,
+            span: Span::default(),
+        }
+    }
 }
 
 impl Codegen for PrototypeAst {
     fn codegen(&self, compiler: &mut Compiler) -> Result<LLVMValueRef> {
-        Ok(compiler.create_proto(&self.name, &self.args))
+        compiler.create_proto(self)
     }
 }
 
@@ -119,10 +413,29 @@ impl Codegen for PrototypeAst {
 pub struct FunctionAst {
     pub proto: PrototypeAst,
     pub body: ExprAst,
+    pub span: Span,
 }
 
 impl Codegen for FunctionAst {
     fn codegen(&self, compiler: &mut Compiler) -> Result<LLVMValueRef> {
         compiler.create_function(self)
     }
+}
+
+/// A statement of a program
+#[derive(Debug)]
+pub enum Item {
+    Definition(FunctionAst),
+    Extern(PrototypeAst),
+    /// top-level expression, wrapped in an anonymous function
+    Expression(FunctionAst),
+}
+
+impl Item {
+    pub fn span(&self) -> Span {
+        match self {
+            Item::Definition(function) | Item::Expression(function) => function.span,
+            Item::Extern(proto) => proto.span,
+        }
+    }
 }
\ No newline at end of file
@@ -1,43 +1,532 @@
-use std::{cell::RefCell, collections::HashMap, ffi::CString, mem::forget};
+use std::{
+    collections::HashMap,
+    ffi::{CStr, CString},
+    mem::{self, forget},
+    path::Path,
+    ptr::null_mut,
+};
 
 use crate::{
-    ast::{Codegen, ExprAst, FunctionAst, OpSymbol}, error::CompileError, jit::KaleicoscopeJit, Result
+    analysis::PassManager,
+    ast::{
+        Codegen, ExprAst, ForExprAst, FunctionAst, IfExprAst, Item, OpSymbol, PrototypeAst,
+        UnaryOpSymbol, VarExprAst,
+    },
+    debug::{DebugInfo, SourceFile},
+    error::CompileError,
+    jit::{KaleicoscopeJit, ResourceTracker},
+    lex::Span,
+    parser::Parser,
+    target::{OptLevel, OutputKind, Target},
+    Result, LLVM_SUCCESS,
 };
 use llvm_sys::{
-    analysis::LLVMVerifyFunction,
+    analysis::{LLVMVerifierFailureAction, LLVMVerifyFunction, LLVMVerifyModule},
     core::{
-        LLVMAddFunction, LLVMAppendBasicBlockInContext, LLVMBuildCall2, LLVMBuildFAdd, LLVMBuildFCmp, LLVMBuildFDiv, LLVMBuildFMul, LLVMBuildFSub, LLVMBuildRet, LLVMBuildUIToFP, LLVMConstReal, LLVMContextCreate, LLVMCountBasicBlocks, LLVMCountParams, LLVMCreateBuilderInContext, LLVMCreateFunctionPassManagerForModule, LLVMCreatePassManager, LLVMDoubleTypeInContext, LLVMEraseGlobalIFunc, LLVMFunctionType, LLVMGetNamedFunction, LLVMGetParams, LLVMGlobalGetValueType, LLVMInitializeFunctionPassManager, LLVMModuleCreateWithNameInContext, LLVMPositionBuilderAtEnd, LLVMPrintModuleToString, LLVMPrintValueToString, LLVMRunFunctionPassManager, LLVMSetDataLayout, LLVMSetValueName2
+        LLVMAddFunction, LLVMAddIncoming, LLVMAppendBasicBlockInContext,
+        LLVMAppendExistingBasicBlock, LLVMBuildAlloca, LLVMBuildBr, LLVMBuildCall2,
+        LLVMBuildCondBr, LLVMBuildFAdd, LLVMBuildFCmp, LLVMBuildFDiv, LLVMBuildFMul, LLVMBuildFNeg,
+        LLVMBuildFPToSI, LLVMBuildFSub, LLVMBuildLoad2, LLVMBuildPhi, LLVMBuildRet, LLVMBuildStore,
+        LLVMBuildUIToFP, LLVMClearInsertionPosition, LLVMCloneModule, LLVMConstInt, LLVMConstReal,
+        LLVMContextCreate, LLVMContextDispose, LLVMCountBasicBlocks, LLVMCountParams,
+        LLVMCreateBasicBlockInContext, LLVMCreateBuilderInContext, LLVMDeleteBasicBlock,
+        LLVMDeleteFunction, LLVMDisposeBuilder, LLVMDisposeMessage, LLVMDisposeModule,
+        LLVMDoubleTypeInContext, LLVMFunctionType, LLVMGetBasicBlockParent,
+        LLVMGetBasicBlockTerminator, LLVMGetEntryBasicBlock, LLVMGetFirstBasicBlock,
+        LLVMGetFirstFunction, LLVMGetFirstInstruction, LLVMGetFirstUse, LLVMGetInsertBlock,
+        LLVMGetModuleIdentifier, LLVMGetNamedFunction, LLVMGetNextBasicBlock, LLVMGetNextFunction,
+        LLVMGetNextInstruction, LLVMGetParams, LLVMGetTypeKind, LLVMGetUndef, LLVMGetValueName2,
+        LLVMGlobalGetValueType, LLVMInstructionEraseFromParent, LLVMInt32TypeInContext,
+        LLVMModuleCreateWithNameInContext, LLVMPositionBuilder, LLVMPositionBuilderAtEnd,
+        LLVMPrintModuleToString, LLVMPrintValueToString, LLVMReplaceAllUsesWith,
+        LLVMSetCurrentDebugLocation2, LLVMSetLinkage, LLVMSetValueName2, LLVMSetVisibility,
+        LLVMTypeOf,
     },
     prelude::*,
-    LLVMRealPredicate,
+    target_machine::LLVMTargetMachineRef,
+    LLVMLinkage, LLVMRealPredicate, LLVMTypeKind, LLVMVisibility,
 };
 
 pub struct Compiler {
     context: LLVMContextRef,
     builder: LLVMBuilderRef,
+    /// module being built, each definition and top-level expression gets a fresh one
     pub(crate) module: LLVMModuleRef,
     names: HashMap<String, LLVMValueRef>,
-    jit: KaleicoscopeJit,
+    /// every known prototype, used to declare functions which live in modules
+    /// already handed to the jit
+    protos: HashMap<String, PrototypeAst>,
+    anon_count: usize,
+    /// name of the definition in `module`, handed to the jit before the next compilation
+    pending: Option<String>,
+    /// tracker of each definition in the jit, with a copy of its module to add it again
+    definitions: HashMap<String, (ResourceTracker, LLVMModuleRef)>,
+    /// source file of the debug information, see [`Compiler::enable_debug_info`]
+    source: Option<SourceFile>,
+    /// debug information of `module`
+    debug: Option<DebugInfo>,
+    /// span of the construct being compiled, see [`Compiler::set_location`]
+    span: Span,
+    /// run on each module, see [`Compiler::set_opt_level`]
+    pass_manager: PassManager,
+    /// `None` when compiling ahead of time, then everything goes in one module
+    jit: Option<KaleicoscopeJit>,
 }
 
 impl Compiler {
     pub fn new() -> Result<Self> {
+        unsafe {
+            let jit = KaleicoscopeJit::create().map_err(CompileError::CreateJitError)?;
+            let context = jit.context();
+            let module = Self::create_module(context, &jit);
+
+            Ok(Self {
+                builder: LLVMCreateBuilderInContext(context),
+                module,
+                context,
+                names: HashMap::new(),
+                protos: HashMap::new(),
+                anon_count: 0,
+                pending: None,
+                definitions: HashMap::new(),
+                source: None,
+                debug: None,
+                span: Span::default(),
+                pass_manager: PassManager::with_opt_level(OptLevel::None),
+                jit: Some(jit),
+            })
+        }
+    }
+
+    /// Compiler for ahead-of-time compilation, all definitions go in the module `name`
+    /// which can be written out by [`Compiler::write_to_file`]
+    pub fn with_target(target: &Target, name: &str) -> Result<Self> {
         unsafe {
             let context = LLVMContextCreate();
-            let name = CString::new("my tool jit").unwrap();
+            let name = CString::new(name).unwrap();
             let module = LLVMModuleCreateWithNameInContext(name.as_ptr(), context);
-            // LLVMSetDataLayout(module, LLVMGetLay);
+            target.setup_module(module);
 
             Ok(Self {
                 builder: LLVMCreateBuilderInContext(context),
                 module,
                 context,
                 names: HashMap::new(),
-                jit: KaleicoscopeJit::create().map_err(|e| Into::<CompileError>::into(e))?,
+                protos: HashMap::new(),
+                anon_count: 0,
+                pending: None,
+                definitions: HashMap::new(),
+                source: None,
+                debug: None,
+                span: Span::default(),
+                pass_manager: PassManager::with_opt_level(OptLevel::None),
+                jit: None,
             })
         }
     }
 
+    /// Write the module to `path`, see [`OutputKind`]
+    pub fn write_to_file<P: AsRef<Path>>(
+        &self,
+        target: &Target,
+        path: P,
+        kind: OutputKind,
+    ) -> Result<()> {
+        if let Some(debug) = &self.debug {
+            debug.finalize();
+        }
+        self.verify_module()?;
+        self.optimize_module(self.module, target.machine())?;
+        target
+            .write_module(self.module, path, kind)
+            .map_err(|e| CompileError::WriteOutputError(e).into())
+    }
+
+    /// Emit DWARF debug information for the code compiled from now on, `path` is the source file.
+    ///
+    /// It is kept by the optimizations, a variable promoted out of its stack slot is
+    /// still described where its value lives.
+    pub fn enable_debug_info<P: AsRef<Path>>(&mut self, path: P) {
+        let source = SourceFile::new(path);
+        self.debug = Some(DebugInfo::new(self.context, self.module, &source));
+        self.source = Some(source);
+    }
+
+    /// Compile the next instructions for the source at `span`, it locates the errors and the
+    /// debug information. Return the previous span, to be restored once the construct is compiled.
+    pub fn set_location(&mut self, span: Span) -> Span {
+        if let Some(debug) = &self.debug {
+            debug.set_location(self.builder, span);
+        }
+        mem::replace(&mut self.span, span)
+    }
+
+    /// Span of the construct being compiled
+    pub fn span(&self) -> Span {
+        self.span
+    }
+
+    fn jit(&self) -> Result<&KaleicoscopeJit> {
+        self.jit
+            .as_ref()
+            .ok_or_else(|| CompileError::JitUnavailable.into())
+    }
+
+    fn create_module(context: LLVMContextRef, jit: &KaleicoscopeJit) -> LLVMModuleRef {
+        unsafe {
+            let name = CString::new("my tool jit").unwrap();
+            let module = LLVMModuleCreateWithNameInContext(name.as_ptr(), context);
+            jit.setup_module(module);
+
+            module
+        }
+    }
+
+    /// Finish the current module and start a new one
+    fn take_module(&mut self) -> Result<LLVMModuleRef> {
+        if let Some(debug) = self.debug.take() {
+            debug.finalize();
+        }
+        self.optimize_module(self.module, self.jit()?.target_machine())?;
+        let module = Self::create_module(self.context, self.jit()?);
+        let module = mem::replace(&mut self.module, module);
+        if let Some(source) = &self.source {
+            self.debug = Some(DebugInfo::new(self.context, self.module, source));
+        }
+
+        Ok(module)
+    }
+
+    /// Hand the current module to the jit and start a new one
+    fn flush_module(&mut self, tracker: &ResourceTracker) -> Result<()> {
+        let module = self.take_module()?;
+        self.jit()?
+            .add_module(module, Some(tracker))
+            .map_err(|e| CompileError::JitError(e).into())
+    }
+
+    /// Hand the pending definition to the jit, replacing any earlier definition with the same name
+    fn flush_definition(&mut self) -> Result<()> {
+        let Some(name) = self.pending.take() else {
+            return Ok(());
+        };
+
+        // code calling the replaced definition may already be linked against it,
+        // so it is removed too and added again to resolve against the new one
+        let dependents = self.dependents(&name);
+        for removed in dependents.iter().chain([&name]) {
+            if let Some((tracker, _)) = self.definitions.get(removed) {
+                tracker.remove().map_err(CompileError::JitError)?;
+            }
+        }
+
+        unsafe {
+            if let Some((_, module)) = self.definitions.remove(&name) {
+                LLVMDisposeModule(module);
+            }
+
+            // copied once its debug information is finalized
+            let module = self.take_module()?;
+            let copy = LLVMCloneModule(module);
+            let tracker = self.jit()?.create_resource_tracker();
+            let added = self.jit()?.add_module(module, Some(&tracker));
+            self.definitions.insert(name, (tracker, copy));
+            added.map_err(CompileError::JitError)?;
+
+            for dependent in dependents {
+                let (_, module) = self.definitions.remove(&dependent).unwrap();
+                let jit = self.jit()?;
+                let tracker = jit.create_resource_tracker();
+                let added = jit.add_module(LLVMCloneModule(module), Some(&tracker));
+                self.definitions.insert(dependent, (tracker, module));
+                added.map_err(CompileError::JitError)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Definitions which call `name`, directly or not
+    fn dependents(&self, name: &str) -> Vec<String> {
+        let mut dependents: Vec<String> = Vec::new();
+        let mut callees = vec![name.to_string()];
+        while let Some(callee) = callees.pop() {
+            let c_callee = CString::new(callee.as_str()).unwrap();
+            for (caller, (_, module)) in &self.definitions {
+                if *caller == callee || caller == name || dependents.contains(caller) {
+                    continue;
+                }
+
+                if unsafe { !LLVMGetNamedFunction(*module, c_callee.as_ptr()).is_null() } {
+                    dependents.push(caller.clone());
+                    callees.push(caller.clone());
+                }
+            }
+        }
+
+        dependents
+    }
+
+    /// Compile the top-level expression `func_ast` (see [`crate::parser::Parser::parse_toplevel`]),
+    /// run it and return its value.
+    ///
+    /// The expression is removed from the jit after it has been run.
+    pub fn eval_toplevel(&mut self, func_ast: &FunctionAst) -> Result<f64> {
+        self.jit()?;
+
+        let function = self.create_function(func_ast)?;
+        let name = value_name(function);
+
+        let tracker = self.jit()?.create_resource_tracker();
+        self.flush_module(&tracker)?;
+
+        let result = self.jit()?.lookup(&name).map(|address| unsafe {
+            let expr: extern "C" fn() -> f64 = mem::transmute(address as usize);
+            expr()
+        });
+        tracker.remove().map_err(CompileError::JitError)?;
+
+        result.map_err(|e| CompileError::JitError(e).into())
+    }
+
+    /// Compile every statement of `source`. Top-level expressions are run with the jit,
+    /// otherwise they are kept in the module to be run by [`Compiler::create_main`].
+    ///
+    /// The whole source is parsed first, nothing is compiled if it has syntax errors
+    /// and the first one is returned, see [`Parser::parse_all`] for all of them.
+    pub fn compile_source(&mut self, source: &str) -> Result<()> {
+        let (items, mut errors) = Parser::new(source)?.parse_all();
+        if !errors.is_empty() {
+            return Err(errors.remove(0));
+        }
+
+        for item in &items {
+            self.compile_item(item)?;
+        }
+        Ok(())
+    }
+
+    /// Compile `item`, a top-level expression is run with the jit
+    pub fn compile_item(&mut self, item: &Item) -> Result<()> {
+        match item {
+            Item::Definition(function) => {
+                function.codegen(self)?;
+            }
+            Item::Extern(proto) => {
+                self.create_proto(proto)?;
+            }
+            Item::Expression(expr) => {
+                if self.jit.is_some() {
+                    self.eval_toplevel(expr)?;
+                } else {
+                    self.create_function(expr)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Define the entry point `i32 main()` of an executable. It runs the top-level expressions
+    /// in order, then `def main()` if there is one, whose value is the exit code.
+    pub fn create_main(&mut self) -> Result<LLVMValueRef> {
+        unsafe {
+            let name = CString::new("main").unwrap();
+            let mut user_main = LLVMGetNamedFunction(self.module, name.as_ptr());
+            if !user_main.is_null() {
+                if LLVMCountBasicBlocks(user_main) == 0 {
+                    // `extern main()` would be the C entry point itself
+                    if !LLVMGetFirstUse(user_main).is_null() {
+                        let span = self.protos.get("main").map(|proto| proto.span);
+                        return Err(CompileError::UnknowFunction(
+                            "main".into(),
+                            span.unwrap_or_default(),
+                        )
+                        .into());
+                    }
+                    LLVMDeleteFunction(user_main);
+                    user_main = null_mut();
+                } else {
+                    let args = LLVMCountParams(user_main) as usize;
+                    if args != 0 {
+                        return Err(CompileError::IncorrectArguments {
+                            expect: 0,
+                            get: args,
+                            span: self
+                                .protos
+                                .get("main")
+                                .map(|proto| proto.span)
+                                .unwrap_or_default(),
+                            declared: None,
+                        }
+                        .into());
+                    }
+                    // the kaleidoscope `main` returns a double, make room for the C one
+                    let renamed = "main.ks";
+                    LLVMSetValueName2(user_main, renamed.as_ptr() as *const _, renamed.len());
+                }
+            }
+
+            let toplevel = (1..=self.anon_count)
+                .map(|idx| {
+                    let name = CString::new(format!("__anon_expr.{idx}")).unwrap();
+                    LLVMGetNamedFunction(self.module, name.as_ptr())
+                })
+                .filter(|function| !function.is_null())
+                .collect::<Vec<_>>();
+            if user_main.is_null() && toplevel.is_empty() {
+                return Err(CompileError::MissingMain.into());
+            }
+
+            let int_type = LLVMInt32TypeInContext(self.context);
+            let function_type = LLVMFunctionType(int_type, null_mut(), 0, 0);
+            let main = LLVMAddFunction(self.module, name.as_ptr(), function_type);
+
+            let name = CString::new("entry").unwrap();
+            let basic_block = LLVMAppendBasicBlockInContext(self.context, main, name.as_ptr());
+            LLVMPositionBuilderAtEnd(self.builder, basic_block);
+            LLVMSetCurrentDebugLocation2(self.builder, null_mut());
+
+            for function in toplevel {
+                self.build_call(function, vec![], "");
+            }
+            let code = if user_main.is_null() {
+                LLVMConstInt(int_type, 0, 0)
+            } else {
+                let value = self.build_call(user_main, vec![], "calltmp");
+                let name = CString::new("code").unwrap();
+                LLVMBuildFPToSI(self.builder, value, int_type, name.as_ptr())
+            };
+            LLVMBuildRet(self.builder, code);
+
+            Ok(main)
+        }
+    }
+
+    /// Prototypes of the defined `export def` functions, sorted by name
+    pub fn exports(&self) -> Vec<&PrototypeAst> {
+        let mut exports = self
+            .protos
+            .values()
+            .filter(|proto| proto.exported && self.is_defined(&proto.name))
+            .collect::<Vec<_>>();
+        exports.sort_by(|a, b| a.name.cmp(&b.name));
+        exports
+    }
+
+    /// Give hidden visibility to every function but the exported ones,
+    /// so that a shared library only exposes [`Compiler::exports`]
+    pub fn hide_unexported(&self) {
+        unsafe {
+            let mut function = LLVMGetFirstFunction(self.module);
+            while !function.is_null() {
+                let mut len = 0;
+                let name = LLVMGetValueName2(function, &mut len);
+                let name = std::slice::from_raw_parts(name as *const u8, len);
+                let exported = self
+                    .protos
+                    .get(String::from_utf8_lossy(name).as_ref())
+                    .is_some_and(|proto| proto.exported);
+                if LLVMCountBasicBlocks(function) > 0 && !exported {
+                    LLVMSetVisibility(function, LLVMVisibility::LLVMHiddenVisibility);
+                }
+                function = LLVMGetNextFunction(function);
+            }
+        }
+    }
+
+    fn is_defined(&self, name: &str) -> bool {
+        self.definition_module(name).is_some()
+    }
+
+    /// Module holding the definition of the function `name`, if it is defined
+    fn definition_module(&self, name: &str) -> Option<LLVMModuleRef> {
+        let module = match self.definitions.get(name) {
+            Some((_, module)) if self.pending.as_deref() != Some(name) => *module,
+            _ => self.module,
+        };
+
+        unsafe {
+            let name = CString::new(name).unwrap();
+            let function = LLVMGetNamedFunction(module, name.as_ptr());
+            (!function.is_null() && LLVMCountBasicBlocks(function) > 0).then_some(module)
+        }
+    }
+
+    /// Prototypes of the known functions sorted by name, with whether they are defined
+    /// (an `extern` is not)
+    pub fn functions(&self) -> Vec<(&PrototypeAst, bool)> {
+        let mut functions = self
+            .protos
+            .values()
+            .map(|proto| (proto, self.is_defined(&proto.name)))
+            .collect::<Vec<_>>();
+        functions.sort_by(|(a, _), (b, _)| a.name.cmp(&b.name));
+        functions
+    }
+
+    /// Llvm ir of the definition of the function `name`
+    pub fn function_ir(&self, name: &str) -> Option<String> {
+        let module = self.definition_module(name)?;
+        unsafe {
+            let name = CString::new(name).unwrap();
+            Some(self.print(LLVMGetNamedFunction(module, name.as_ptr())))
+        }
+    }
+
+    /// Assembly of the definition of the function `name` for `target`
+    pub fn function_asm(&self, name: &str, target: &Target) -> Result<Option<String>> {
+        let Some(module) = self.definition_module(name) else {
+            return Ok(None);
+        };
+
+        unsafe {
+            // the other definitions of a copy of the module are not emitted
+            let module = LLVMCloneModule(module);
+            let mut function = LLVMGetFirstFunction(module);
+            while !function.is_null() {
+                let next = LLVMGetNextFunction(function);
+                if value_name(function) != name && LLVMCountBasicBlocks(function) > 0 {
+                    LLVMSetLinkage(function, LLVMLinkage::LLVMAvailableExternallyLinkage);
+                }
+                function = next;
+            }
+
+            target.setup_module(module);
+            let asm = target.emit_assembly(module);
+            LLVMDisposeModule(module);
+
+            asm.map(Some)
+                .map_err(|e| CompileError::WriteOutputError(e).into())
+        }
+    }
+
+    /// Run the `default<On>` pipeline of `opt_level` on each module compiled from now on,
+    /// `-O0` by default.
+    pub fn set_opt_level(&mut self, opt_level: OptLevel) {
+        self.pass_manager = PassManager::with_opt_level(opt_level);
+    }
+
+    /// Run a custom pipeline on each module compiled from now on, see [`PassManager::with_passes`]
+    pub fn set_passes(&mut self, passes: &str) -> Result<()> {
+        let pass_manager = PassManager::with_passes(passes);
+        // a wrong pipeline is reported now rather than by the next module
+        unsafe {
+            let name = CString::new("passes").unwrap();
+            let module = LLVMModuleCreateWithNameInContext(name.as_ptr(), self.context);
+            let checked = pass_manager.run(module, None);
+            LLVMDisposeModule(module);
+            checked?;
+        }
+
+        self.pass_manager = pass_manager;
+        Ok(())
+    }
+
+    /// Optimizations run on each module, e.g. to toggle the vectorization
+    pub fn pass_manager(&self) -> &PassManager {
+        &self.pass_manager
+    }
+
     pub fn double_type(&self) -> LLVMTypeRef {
         unsafe { LLVMDoubleTypeInContext(self.context) }
     }
@@ -64,40 +553,137 @@ impl Compiler {
         right: LLVMValueRef,
         name: &str,
         op: OpSymbol,
-    ) -> LLVMValueRef {
+    ) -> Result<LLVMValueRef> {
         unsafe {
-            let name = CString::new(name).unwrap();
+            let c_name = CString::new(name).unwrap();
 
             match op {
-                OpSymbol::Add => LLVMBuildFAdd(self.builder, left, right, name.as_ptr()),
-                OpSymbol::Sub => LLVMBuildFSub(self.builder, left, right, name.as_ptr()),
-                OpSymbol::Mul => LLVMBuildFMul(self.builder, left, right, name.as_ptr()),
-                OpSymbol::Div => LLVMBuildFDiv(self.builder, left, right, name.as_ptr()),
-                OpSymbol::Less | OpSymbol::Greater => {
-                    let cmp = if let OpSymbol::Less = op {
-                        LLVMBuildFCmp(
-                            self.builder,
-                            LLVMRealPredicate::LLVMRealULT,
-                            left,
-                            right,
-                            name.as_ptr(),
-                        )
-                    } else {
-                        LLVMBuildFCmp(
-                            self.builder,
-                            LLVMRealPredicate::LLVMRealULT,
-                            right,
-                            left,
-                            name.as_ptr(),
-                        )
-                    };
+                OpSymbol::Add => Ok(LLVMBuildFAdd(self.builder, left, right, c_name.as_ptr())),
+                OpSymbol::Sub => Ok(LLVMBuildFSub(self.builder, left, right, c_name.as_ptr())),
+                OpSymbol::Mul => Ok(LLVMBuildFMul(self.builder, left, right, c_name.as_ptr())),
+                OpSymbol::Div => Ok(LLVMBuildFDiv(self.builder, left, right, c_name.as_ptr())),
+                OpSymbol::Less
+                | OpSymbol::Greater
+                | OpSymbol::LessEq
+                | OpSymbol::GreaterEq
+                | OpSymbol::Equal
+                | OpSymbol::NotEq => {
+                    let predicate = op.predicate().unwrap();
+                    let cmp = LLVMBuildFCmp(self.builder, predicate, left, right, c_name.as_ptr());
                     let bool_name = CString::new("booltmp").unwrap();
-                    LLVMBuildUIToFP(
+                    Ok(LLVMBuildUIToFP(
                         self.builder,
                         cmp,
                         LLVMDoubleTypeInContext(self.context),
                         bool_name.as_ptr(),
-                    )
+                    ))
+                }
+                // see `Compiler::create_assign` and `Compiler::create_logical`
+                OpSymbol::Assign | OpSymbol::And | OpSymbol::Or => {
+                    Err(CompileError::UnsupportedBinaryOperator(op, self.span).into())
+                }
+                OpSymbol::Custom(op) => {
+                    let function = self.get_function(&format!("binary{op}"), 2)?;
+                    Ok(self.build_call(function, vec![left, right], name))
+                }
+            }
+        }
+    }
+
+    /// Short-circuiting `&&` and `||`, the result is `1.0` or `0.0`
+    pub fn create_logical(
+        &mut self,
+        lhs: &ExprAst,
+        rhs: &ExprAst,
+        op: OpSymbol,
+    ) -> Result<LLVMValueRef> {
+        let (name, short_circuit) = match op {
+            OpSymbol::And => ("andtmp", 0.0),
+            OpSymbol::Or => ("ortmp", 1.0),
+            _ => return Err(CompileError::UnsupportedBinaryOperator(op, self.span).into()),
+        };
+
+        unsafe {
+            let left = self.create_truth(lhs)?;
+            let mut left_bb = LLVMGetInsertBlock(self.builder);
+            let function = LLVMGetBasicBlockParent(left_bb);
+
+            let rhs_name = CString::new(format!("{name}.rhs")).unwrap();
+            let merge_name = CString::new(format!("{name}.cont")).unwrap();
+            let rhs_bb = LLVMAppendBasicBlockInContext(self.context, function, rhs_name.as_ptr());
+            let merge_bb = LLVMCreateBasicBlockInContext(self.context, merge_name.as_ptr());
+
+            if let OpSymbol::And = op {
+                LLVMBuildCondBr(self.builder, left, rhs_bb, merge_bb);
+            } else {
+                LLVMBuildCondBr(self.builder, left, merge_bb, rhs_bb);
+            }
+
+            LLVMPositionBuilderAtEnd(self.builder, rhs_bb);
+            let right = self.create_truth(rhs)?;
+            let bool_name = CString::new("booltmp").unwrap();
+            let mut right =
+                LLVMBuildUIToFP(self.builder, right, self.double_type(), bool_name.as_ptr());
+            LLVMBuildBr(self.builder, merge_bb);
+            let mut right_bb = LLVMGetInsertBlock(self.builder);
+
+            LLVMAppendExistingBasicBlock(function, merge_bb);
+            LLVMPositionBuilderAtEnd(self.builder, merge_bb);
+            let name = CString::new(name).unwrap();
+            let phi = LLVMBuildPhi(self.builder, self.double_type(), name.as_ptr());
+            let mut short_circuit = self.const_double(short_circuit);
+            LLVMAddIncoming(phi, &mut short_circuit, &mut left_bb, 1);
+            LLVMAddIncoming(phi, &mut right, &mut right_bb, 1);
+
+            Ok(phi)
+        }
+    }
+
+    /// Evaluate `expr` as an `i1`, true if it is not `0.0`
+    fn create_truth(&mut self, expr: &ExprAst) -> Result<LLVMValueRef> {
+        let value = expr.codegen(self)?;
+        unsafe {
+            let name = CString::new("tobool").unwrap();
+            Ok(LLVMBuildFCmp(
+                self.builder,
+                LLVMRealPredicate::LLVMRealONE,
+                value,
+                self.const_double(0.0),
+                name.as_ptr(),
+            ))
+        }
+    }
+
+    pub fn create_unary(
+        &self,
+        operand: LLVMValueRef,
+        name: &str,
+        op: UnaryOpSymbol,
+    ) -> Result<LLVMValueRef> {
+        unsafe {
+            let c_name = CString::new(name).unwrap();
+
+            match op {
+                UnaryOpSymbol::Neg => Ok(LLVMBuildFNeg(self.builder, operand, c_name.as_ptr())),
+                UnaryOpSymbol::Not => {
+                    let cmp_name = CString::new("cmptmp").unwrap();
+                    let cmp = LLVMBuildFCmp(
+                        self.builder,
+                        LLVMRealPredicate::LLVMRealOEQ,
+                        operand,
+                        self.const_double(0.0),
+                        cmp_name.as_ptr(),
+                    );
+                    Ok(LLVMBuildUIToFP(
+                        self.builder,
+                        cmp,
+                        self.double_type(),
+                        c_name.as_ptr(),
+                    ))
+                }
+                UnaryOpSymbol::Custom(op) => {
+                    let function = self.get_function(&format!("unary{op}"), 1)?;
+                    Ok(self.build_call(function, vec![operand], name))
                 }
             }
         }
@@ -106,51 +692,317 @@ impl Compiler {
     pub fn create_call(
         &mut self,
         call: &str,
-        args: &Vec<ExprAst>,
+        args: &[ExprAst],
         name: &str,
     ) -> Result<LLVMValueRef> {
+        let function = self.get_function(call, args.len())?;
+
+        let mut args_val = Vec::new();
+        for arg in args {
+            let arg_v = arg.codegen(self)?;
+            if arg_v.is_null() {
+                return Err(CompileError::FunctionArgumentIsNull.into());
+            }
+            args_val.push(arg_v);
+        }
+
+        Ok(self.build_call(function, args_val, name))
+    }
+
+    /// Find the function `call` and check it takes `args_size` arguments.
+    ///
+    /// Functions living in other modules are declared in the current module.
+    fn get_function(&self, call: &str, args_size: usize) -> Result<LLVMValueRef> {
         unsafe {
-            let call = CString::new(call).unwrap();
-            let function = LLVMGetNamedFunction(self.module, call.as_ptr());
+            let c_call = CString::new(call).unwrap();
+            let mut function = LLVMGetNamedFunction(self.module, c_call.as_ptr());
             if function.is_null() {
-                return Err(
-                    CompileError::UnknowFunction(call.to_string_lossy().to_string()).into(),
-                );
+                function = match self.protos.get(call) {
+                    Some(proto) => self.emit_proto(&proto.name, &proto.args),
+                    None => {
+                        return Err(CompileError::UnknowFunction(call.to_string(), self.span).into())
+                    }
+                };
             }
 
-            let args_size = LLVMCountParams(function);
-            if args_size as usize != args.len() {
+            let expect = LLVMCountParams(function) as usize;
+            if expect != args_size {
                 return Err(CompileError::IncorrectArguments {
-                    expect: args_size as usize,
-                    get: args.len(),
+                    expect,
+                    get: args_size,
+                    span: self.span,
+                    declared: self.protos.get(call).map(|proto| proto.span),
                 }
                 .into());
             }
 
-            let mut args_val = Vec::new();
-            let rc = RefCell::new(self);
-            for arg in args {
-                let arg_v = arg.codegen(&mut rc.borrow_mut())?;
-                if arg_v.is_null() {
-                    return Err(CompileError::FunctionArgumentIsNull.into());
-                }
-                args_val.push(arg_v);
-            }
+            Ok(function)
+        }
+    }
 
+    fn build_call(
+        &self,
+        function: LLVMValueRef,
+        mut args: Vec<LLVMValueRef>,
+        name: &str,
+    ) -> LLVMValueRef {
+        unsafe {
             let name = CString::new(name).unwrap();
-            let builder = rc.borrow().builder;
-            Ok(LLVMBuildCall2(
-                builder,
+            LLVMBuildCall2(
+                self.builder,
                 LLVMGlobalGetValueType(function),
                 function,
-                args_val.as_mut_ptr(),
-                args_size,
+                args.as_mut_ptr(),
+                args.len() as u32,
                 name.as_ptr(),
+            )
+        }
+    }
+
+    pub fn create_if(&mut self, if_ast: &IfExprAst) -> Result<LLVMValueRef> {
+        unsafe {
+            let cond = if_ast.cond.codegen(self)?;
+            let cond_name = CString::new("ifcond").unwrap();
+            let cond = LLVMBuildFCmp(
+                self.builder,
+                LLVMRealPredicate::LLVMRealONE,
+                cond,
+                self.const_double(0.0),
+                cond_name.as_ptr(),
+            );
+
+            let function = LLVMGetBasicBlockParent(LLVMGetInsertBlock(self.builder));
+
+            let then_name = CString::new("then").unwrap();
+            let else_name = CString::new("else").unwrap();
+            let merge_name = CString::new("ifcont").unwrap();
+            let mut then_bb =
+                LLVMAppendBasicBlockInContext(self.context, function, then_name.as_ptr());
+            let mut else_bb = LLVMCreateBasicBlockInContext(self.context, else_name.as_ptr());
+            let merge_bb = LLVMCreateBasicBlockInContext(self.context, merge_name.as_ptr());
+
+            LLVMBuildCondBr(self.builder, cond, then_bb, else_bb);
+
+            LLVMPositionBuilderAtEnd(self.builder, then_bb);
+            let mut then_val = if_ast.then.codegen(self)?;
+            LLVMBuildBr(self.builder, merge_bb);
+            // codegen of `then` can change the current block, update it for the phi
+            then_bb = LLVMGetInsertBlock(self.builder);
+
+            LLVMAppendExistingBasicBlock(function, else_bb);
+            LLVMPositionBuilderAtEnd(self.builder, else_bb);
+            let mut else_val = if_ast.otherwise.codegen(self)?;
+            LLVMBuildBr(self.builder, merge_bb);
+            else_bb = LLVMGetInsertBlock(self.builder);
+
+            LLVMAppendExistingBasicBlock(function, merge_bb);
+            LLVMPositionBuilderAtEnd(self.builder, merge_bb);
+            let phi_name = CString::new("iftmp").unwrap();
+            let phi = LLVMBuildPhi(self.builder, self.double_type(), phi_name.as_ptr());
+            LLVMAddIncoming(phi, &mut then_val, &mut then_bb, 1);
+            LLVMAddIncoming(phi, &mut else_val, &mut else_bb, 1);
+
+            Ok(phi)
+        }
+    }
+
+    pub fn create_for(&mut self, for_ast: &ForExprAst) -> Result<LLVMValueRef> {
+        unsafe {
+            let function = LLVMGetBasicBlockParent(LLVMGetInsertBlock(self.builder));
+            let alloca = self.create_entry_block_alloca(function, &for_ast.var);
+
+            let start = for_ast.start.codegen(self)?;
+            LLVMBuildStore(self.builder, start, alloca);
+
+            let loop_name = CString::new("loop").unwrap();
+            let loop_bb = LLVMAppendBasicBlockInContext(self.context, function, loop_name.as_ptr());
+
+            LLVMBuildBr(self.builder, loop_bb);
+            LLVMPositionBuilderAtEnd(self.builder, loop_bb);
+
+            // the induction variable shadows any outer binding inside the loop
+            let shadowed = self.names.insert(for_ast.var.clone(), alloca);
+            let end_cond = self.create_for_step(for_ast, alloca);
+            match shadowed {
+                Some(shadowed) => self.names.insert(for_ast.var.clone(), shadowed),
+                None => self.names.remove(&for_ast.var),
+            };
+            let end_cond = end_cond?;
+
+            let after_name = CString::new("afterloop").unwrap();
+            let after_bb =
+                LLVMAppendBasicBlockInContext(self.context, function, after_name.as_ptr());
+
+            LLVMBuildCondBr(self.builder, end_cond, loop_bb, after_bb);
+            LLVMPositionBuilderAtEnd(self.builder, after_bb);
+
+            Ok(self.const_double(0.0))
+        }
+    }
+
+    /// Emit the loop body, the increment of the induction variable and the end condition.
+    fn create_for_step(
+        &mut self,
+        for_ast: &ForExprAst,
+        alloca: LLVMValueRef,
+    ) -> Result<LLVMValueRef> {
+        unsafe {
+            for_ast.body.codegen(self)?;
+
+            let step = match &for_ast.step {
+                Some(step) => step.codegen(self)?,
+                None => self.const_double(1.0),
+            };
+
+            // the end condition is computed before the increment, as the body may change the variable
+            let end = for_ast.end.codegen(self)?;
+
+            let var_name = CString::new(for_ast.var.as_str()).unwrap();
+            let current =
+                LLVMBuildLoad2(self.builder, self.double_type(), alloca, var_name.as_ptr());
+            let next_name = CString::new("nextvar").unwrap();
+            let next = LLVMBuildFAdd(self.builder, current, step, next_name.as_ptr());
+            LLVMBuildStore(self.builder, next, alloca);
+
+            let cond_name = CString::new("loopcond").unwrap();
+            Ok(LLVMBuildFCmp(
+                self.builder,
+                LLVMRealPredicate::LLVMRealONE,
+                end,
+                self.const_double(0.0),
+                cond_name.as_ptr(),
             ))
         }
     }
 
-    pub fn create_proto(&mut self, name: &str, args: &Vec<String>) -> LLVMValueRef {
+    pub fn create_var(&mut self, var_ast: &VarExprAst) -> Result<LLVMValueRef> {
+        unsafe {
+            let function = LLVMGetBasicBlockParent(LLVMGetInsertBlock(self.builder));
+
+            let mut shadowed = Vec::with_capacity(var_ast.vars.len());
+            let mut result = Ok(self.const_double(0.0));
+            for (name, init) in &var_ast.vars {
+                // evaluate the initializer before binding, so `var a = a in ...` refers to the outer `a`
+                let init = match init {
+                    Some(init) => match init.codegen(self) {
+                        Ok(init) => init,
+                        Err(e) => {
+                            result = Err(e);
+                            break;
+                        }
+                    },
+                    None => self.const_double(0.0),
+                };
+
+                let alloca = self.create_entry_block_alloca(function, name);
+                LLVMBuildStore(self.builder, init, alloca);
+
+                shadowed.push((name, self.names.insert(name.clone(), alloca)));
+            }
+
+            if result.is_ok() {
+                result = var_ast.body.codegen(self);
+            }
+
+            // restore the bindings in reverse order, `var a = 1, a = 2 in ...` binds `a` twice
+            for (name, shadowed) in shadowed.into_iter().rev() {
+                match shadowed {
+                    Some(shadowed) => self.names.insert(name.clone(), shadowed),
+                    None => self.names.remove(name),
+                };
+            }
+
+            result
+        }
+    }
+
+    pub fn create_load(&self, name: &str) -> Result<LLVMValueRef> {
+        match self.variable(name) {
+            Some(alloca) => unsafe {
+                let c_name = CString::new(name).unwrap();
+                Ok(LLVMBuildLoad2(
+                    self.builder,
+                    self.double_type(),
+                    alloca,
+                    c_name.as_ptr(),
+                ))
+            },
+            None => Err(CompileError::UnknowVariableName(name.to_string(), self.span).into()),
+        }
+    }
+
+    /// Store `value` into variable `name`, the value of an assignment is the assigned value
+    pub fn create_assign(&mut self, name: &str, value: &ExprAst) -> Result<LLVMValueRef> {
+        let value = value.codegen(self)?;
+        match self.variable(name) {
+            Some(alloca) => unsafe {
+                LLVMBuildStore(self.builder, value, alloca);
+                Ok(value)
+            },
+            None => Err(CompileError::UnknowVariableName(name.to_string(), self.span).into()),
+        }
+    }
+
+    /// Create an `alloca` in the entry block of `function`, so that mem2reg can promote it
+    fn create_entry_block_alloca(&self, function: LLVMValueRef, name: &str) -> LLVMValueRef {
+        unsafe {
+            let builder = LLVMCreateBuilderInContext(self.context);
+            let entry = LLVMGetEntryBasicBlock(function);
+            let first = LLVMGetFirstInstruction(entry);
+            if first.is_null() {
+                LLVMPositionBuilderAtEnd(builder, entry);
+            } else {
+                LLVMPositionBuilder(builder, entry, first);
+            }
+
+            let name = CString::new(name).unwrap();
+            let alloca = LLVMBuildAlloca(builder, self.double_type(), name.as_ptr());
+            LLVMDisposeBuilder(builder);
+
+            alloca
+        }
+    }
+
+    /// Optimize `module` for `machine` with the pass manager, see [`Compiler::set_opt_level`].
+    ///
+    /// The `alloca` of variables are promoted back to SSA registers first, even at `-O0`,
+    /// unless a custom pipeline is run instead.
+    fn optimize_module(&self, module: LLVMModuleRef, machine: LLVMTargetMachineRef) -> Result<()> {
+        if self.pass_manager.opt_level().is_some() {
+            PassManager::with_passes("function(mem2reg,sroa)").run(module, None)?;
+        }
+        self.pass_manager.run(module, Some(machine))
+    }
+
+    /// Declare `proto` in the current module and remember it for later modules.
+    ///
+    /// A function already in the module is reused, it must take as many arguments.
+    pub fn create_proto(&mut self, proto: &PrototypeAst) -> Result<LLVMValueRef> {
+        unsafe {
+            let name = CString::new(proto.name.as_str()).unwrap();
+            let function = LLVMGetNamedFunction(self.module, name.as_ptr());
+            if !function.is_null() {
+                let expect = LLVMCountParams(function) as usize;
+                if expect != proto.args.len() {
+                    return Err(CompileError::IncorrectArguments {
+                        expect,
+                        get: proto.args.len(),
+                        span: proto.span,
+                        declared: self.protos.get(&proto.name).map(|proto| proto.span),
+                    }
+                    .into());
+                }
+
+                self.protos.insert(proto.name.clone(), proto.clone());
+                return Ok(function);
+            }
+        }
+
+        self.protos.insert(proto.name.clone(), proto.clone());
+        Ok(self.emit_proto(&proto.name, &proto.args))
+    }
+
+    fn emit_proto(&self, name: &str, args: &[String]) -> LLVMValueRef {
         unsafe {
             let mut doubles = vec![self.double_type(); args.len()];
             let function_type = LLVMFunctionType(
@@ -179,62 +1031,216 @@ impl Compiler {
         }
     }
 
+    /// Compile `func_ast` into a fresh module, a prototype with an empty name
+    /// (a top-level expression) gets an unique name `__anon_expr.<n>`.
+    ///
+    /// With the jit, a named function replaces any earlier definition with the same name.
+    /// It is handed to the jit at the next compilation, so the returned value is valid until then.
     pub fn create_function(&mut self, func_ast: &FunctionAst) -> Result<LLVMValueRef> {
-        unsafe {
-            let name = CString::new(func_ast.proto.name.as_str()).unwrap();
-            let mut function = LLVMGetNamedFunction(self.module, name.as_ptr());
+        self.flush_definition()?;
+        self.span = func_ast.span;
 
-            if function.is_null() {
-                function = func_ast.proto.codegen(self)?;
+        let name = &func_ast.proto.name;
+        // what to restore if the definition fails
+        let previous = self.protos.get(name).cloned();
+        let declared = unsafe {
+            let name = CString::new(name.as_str()).unwrap();
+            !LLVMGetNamedFunction(self.module, name.as_ptr()).is_null()
+        };
+
+        // the jitted callers of a redefinition are kept as they were compiled,
+        // so they could not call it with another number of arguments
+        if let Some(previous) = &previous {
+            let get = func_ast.proto.args.len();
+            let changed = previous.args.len() != get;
+            if self.jit.is_some() && changed && !self.dependents(name).is_empty() {
+                return Err(CompileError::IncorrectArguments {
+                    expect: previous.args.len(),
+                    get,
+                    span: func_ast.proto.span,
+                    declared: Some(previous.span),
+                }
+                .into());
             }
+        }
+
+        unsafe {
+            let function = if name.is_empty() {
+                self.anon_count += 1;
+                self.emit_proto(&format!("__anon_expr.{}", self.anon_count), &[])
+            } else {
+                func_ast.proto.codegen(self)?
+            };
 
             if function.is_null() {
+                self.restore_proto(name, previous);
                 return Err(CompileError::PointerIsNull.into());
             }
 
             if LLVMCountBasicBlocks(function) >= 1 {
-                return Err(CompileError::FunctionRedifined.into());
+                self.restore_proto(name, previous);
+                return Err(CompileError::FunctionRedifined(func_ast.span).into());
             }
 
-            let name = CString::new("entry").unwrap();
-            let basic_block = LLVMAppendBasicBlockInContext(self.context, function, name.as_ptr());
+            match self.build_body(function, func_ast) {
+                Ok(()) => {
+                    if self.jit.is_some() && !name.is_empty() {
+                        self.pending = Some(name.clone());
+                    }
 
-            LLVMPositionBuilderAtEnd(self.builder, basic_block);
+                    Ok(function)
+                }
+                Err(e) => {
+                    // roll back to the state before the definition
+                    self.names.clear();
+                    LLVMClearInsertionPosition(self.builder);
+                    Self::delete_body(function);
+                    if !declared {
+                        LLVMDeleteFunction(function);
+                    }
+                    self.restore_proto(name, previous);
+                    Err(e)
+                }
+            }
+        }
+    }
 
-            self.names.clear();
+    /// Compile the body of `func_ast` into `function`, then verify it
+    unsafe fn build_body(&mut self, function: LLVMValueRef, func_ast: &FunctionAst) -> Result<()> {
+        let name = CString::new("entry").unwrap();
+        let basic_block = LLVMAppendBasicBlockInContext(self.context, function, name.as_ptr());
 
-            let param_count = LLVMCountParams(function);
-            let mut func_params = Vec::with_capacity(param_count as usize);
-            let ptr = func_params.as_mut_ptr();
-            forget(func_params);
-            LLVMGetParams(function, ptr);
-            let func_params = Vec::from_raw_parts(ptr, param_count as usize, param_count as usize);
+        LLVMPositionBuilderAtEnd(self.builder, basic_block);
+        LLVMSetCurrentDebugLocation2(self.builder, null_mut());
+        if let Some(debug) = &mut self.debug {
+            let name = value_name(function);
+            debug.create_function(function, &name, &func_ast.proto, func_ast.span);
+        }
+        self.set_location(func_ast.proto.span);
 
-            for (idx, arg) in func_params.into_iter().enumerate() {
-                self.names.insert(func_ast.proto.args[idx].clone(), arg);
+        self.names.clear();
+
+        let param_count = LLVMCountParams(function);
+        let mut func_params = Vec::with_capacity(param_count as usize);
+        let ptr = func_params.as_mut_ptr();
+        forget(func_params);
+        LLVMGetParams(function, ptr);
+        let func_params = Vec::from_raw_parts(ptr, param_count as usize, param_count as usize);
+
+        // parameters live in stack slots as well, so that they can be assigned
+        for (idx, arg) in func_params.into_iter().enumerate() {
+            let alloca = self.create_entry_block_alloca(function, &func_ast.proto.args[idx]);
+            LLVMBuildStore(self.builder, arg, alloca);
+            if let Some(debug) = &self.debug {
+                let (name, span) = (&func_ast.proto.args[idx], func_ast.proto.span);
+                debug.declare_parameter(self.builder, alloca, name, idx as u32 + 1, span);
             }
+            self.names.insert(func_ast.proto.args[idx].clone(), alloca);
+        }
 
-            match func_ast.body.codegen(self) {
-                Ok(val) => {
-                    LLVMBuildRet(self.builder, val);
+        let body = func_ast.body.codegen(self);
+        if let Some(debug) = &mut self.debug {
+            debug.finish_function();
+        }
 
-                    LLVMVerifyFunction(
-                        function,
-                        llvm_sys::analysis::LLVMVerifierFailureAction::LLVMPrintMessageAction,
-                    );
+        LLVMBuildRet(self.builder, body?);
+        self.verify_function(function)
+    }
 
-                    let pass = LLVMCreateFunctionPassManagerForModule(self.module);
-                    LLVMInitializeFunctionPassManager(pass);
+    /// Put back the prototype `name` had before a failed definition
+    fn restore_proto(&mut self, name: &str, previous: Option<PrototypeAst>) {
+        match previous {
+            Some(proto) => self.protos.insert(name.to_string(), proto),
+            None => self.protos.remove(name),
+        };
+    }
 
-                    LLVMRunFunctionPassManager(pass, function);
+    /// Check the code generated for `function` with the llvm verifier
+    fn verify_function(&self, function: LLVMValueRef) -> Result<()> {
+        unsafe {
+            let action = LLVMVerifierFailureAction::LLVMReturnStatusAction;
+            if LLVMVerifyFunction(function, action) == LLVM_SUCCESS {
+                return Ok(());
+            }
+        }
 
-                    Ok(function)
+        // only the module verifier gives a message
+        Err(CompileError::InvalidIr {
+            function: value_name(function),
+            message: self.verifier_message().unwrap_or_default(),
+        }
+        .into())
+    }
+
+    /// Check the whole module with the llvm verifier, the functions are already checked one
+    /// by one as they are compiled
+    pub fn verify_module(&self) -> Result<()> {
+        unsafe {
+            let mut function = LLVMGetFirstFunction(self.module);
+            while !function.is_null() {
+                if LLVMCountBasicBlocks(function) > 0 {
+                    self.verify_function(function)?;
                 }
-                Err(e) => {
-                    LLVMEraseGlobalIFunc(function);
-                    Err(e)
+                function = LLVMGetNextFunction(function);
+            }
+
+            let Some(message) = self.verifier_message() else {
+                return Ok(());
+            };
+            let mut len = 0;
+            let name = LLVMGetModuleIdentifier(self.module, &mut len);
+            let name = std::slice::from_raw_parts(name as *const u8, len);
+            Err(CompileError::InvalidIr {
+                function: String::from_utf8_lossy(name).into_owned(),
+                message,
+            }
+            .into())
+        }
+    }
+
+    /// What the verifier finds wrong with the module, if anything
+    fn verifier_message(&self) -> Option<String> {
+        unsafe {
+            let mut message = null_mut();
+            let action = LLVMVerifierFailureAction::LLVMReturnStatusAction;
+            let broken = LLVMVerifyModule(self.module, action, &mut message) != LLVM_SUCCESS;
+            let text = if message.is_null() {
+                String::new()
+            } else {
+                let text = CStr::from_ptr(message)
+                    .to_string_lossy()
+                    .trim_end()
+                    .to_string();
+                LLVMDisposeMessage(message);
+                text
+            };
+
+            broken.then_some(text)
+        }
+    }
+
+    /// Remove the instructions of `function`, leaving a declaration
+    unsafe fn delete_body(function: LLVMValueRef) {
+        // the instructions may be used across blocks, and the blocks by the terminators
+        let mut block = LLVMGetFirstBasicBlock(function);
+        while !block.is_null() {
+            let mut instruction = LLVMGetFirstInstruction(block);
+            while !instruction.is_null() {
+                let ty = LLVMTypeOf(instruction);
+                if LLVMGetTypeKind(ty) != LLVMTypeKind::LLVMVoidTypeKind {
+                    LLVMReplaceAllUsesWith(instruction, LLVMGetUndef(ty));
                 }
+                instruction = LLVMGetNextInstruction(instruction);
+            }
+            let terminator = LLVMGetBasicBlockTerminator(block);
+            if !terminator.is_null() {
+                LLVMInstructionEraseFromParent(terminator);
             }
+            block = LLVMGetNextBasicBlock(block);
+        }
+
+        while !LLVMGetFirstBasicBlock(function).is_null() {
+            LLVMDeleteBasicBlock(LLVMGetFirstBasicBlock(function));
         }
     }
 
@@ -253,8 +1259,44 @@ impl Compiler {
     }
 }
 
+/// Name of the llvm `value`
+fn value_name(value: LLVMValueRef) -> String {
+    unsafe {
+        let mut len = 0;
+        let name = LLVMGetValueName2(value, &mut len);
+        String::from_utf8_lossy(std::slice::from_raw_parts(name as *const u8, len)).into_owned()
+    }
+}
+
+impl Drop for Compiler {
+    fn drop(&mut self) {
+        // the debug information refers to `module`
+        self.debug = None;
+
+        unsafe {
+            LLVMDisposeBuilder(self.builder);
+            LLVMDisposeModule(self.module);
+            for (_, module) in self.definitions.values() {
+                LLVMDisposeModule(*module);
+            }
+            // with the jit the context belongs to it
+            if self.jit.is_none() {
+                LLVMContextDispose(self.context);
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
+    use crate::compile::Compiler;
+
+    /// Ir of the definition `name`, once its module is optimized by the next expression
+    fn optimized_ir(compiler: &mut Compiler, name: &str) -> String {
+        compiler.compile_source("0").unwrap();
+        compiler.function_ir(name).unwrap()
+    }
+
     #[test]
     fn test_method() {
         use crate::ast::*;
@@ -270,4 +1312,421 @@ mod test {
             Err(er) => println!("{:?}", er),
         }
     }
+
+    #[test]
+    fn test_if() {
+        use crate::ast::*;
+        use crate::compile::Compiler;
+        use crate::parser::*;
+
+        let mut parser = Parser::new("def choose(x) if x then 1 else choose(x - 1);").unwrap();
+        let ast = parser.parse_definition().unwrap();
+
+        let mut compiler = Compiler::new().unwrap();
+        ast.codegen(&mut compiler).unwrap();
+        let ir = optimized_ir(&mut compiler, &ast.proto.name);
+        assert!(ir.contains("iftmp = phi double"));
+    }
+
+    #[test]
+    fn test_for() {
+        use crate::ast::*;
+        use crate::compile::Compiler;
+        use crate::parser::*;
+
+        let mut parser = Parser::new("def count(n) for i = 1, n - i in i;").unwrap();
+        let ast = parser.parse_definition().unwrap();
+
+        let mut compiler = Compiler::new().unwrap();
+        ast.codegen(&mut compiler).unwrap();
+        let ir = optimized_ir(&mut compiler, &ast.proto.name);
+        assert!(ir.contains("phi double"));
+        assert!(ir.contains("afterloop:"));
+        assert!(!ir.contains("alloca"));
+        assert!(compiler.variable("i").is_none());
+    }
+
+    #[test]
+    fn test_custom_binary() {
+        use crate::ast::*;
+        use crate::compile::Compiler;
+        use crate::parser::*;
+
+        let mut parser = Parser::new("def binary| 5 (a b) a + b").unwrap();
+        let op = parser.parse_definition().unwrap();
+        assert_eq!(op.proto.name, "binary|");
+        assert_eq!(parser.precedence().get(&OpSymbol::Custom('|')), Some(&5));
+
+        let mut parser =
+            Parser::with_precedence("def use(x) x | 2 * x;", parser.precedence().clone()).unwrap();
+        let ast = parser.parse_definition().unwrap();
+
+        let mut compiler = Compiler::new().unwrap();
+        op.codegen(&mut compiler).unwrap();
+        ast.codegen(&mut compiler).unwrap();
+        let ir = optimized_ir(&mut compiler, &ast.proto.name);
+        assert!(ir.contains("@\"binary|\"(double %x, double %multmp)"));
+    }
+
+    #[test]
+    fn test_unary() {
+        use crate::ast::*;
+        use crate::compile::Compiler;
+        use crate::parser::*;
+
+        let mut parser = Parser::new("def unary~ (v) 0 - v").unwrap();
+        let op = parser.parse_definition().unwrap();
+        assert_eq!(op.proto.name, "unary~");
+
+        let mut parser = Parser::new("def use(x) -3 * ~x + !x;").unwrap();
+        let ast = parser.parse_definition().unwrap();
+
+        let mut compiler = Compiler::new().unwrap();
+        op.codegen(&mut compiler).unwrap();
+        ast.codegen(&mut compiler).unwrap();
+        let ir = optimized_ir(&mut compiler, &ast.proto.name);
+        assert!(ir.contains("fmul double -3.000000e+00"));
+        assert!(ir.contains("@\"unary~\"(double %x)"));
+        assert!(ir.contains("nottmp"));
+    }
+
+    #[test]
+    fn test_var() {
+        use crate::ast::*;
+        use crate::compile::Compiler;
+        use crate::parser::*;
+
+        let mut parser = Parser::new("def acc(x) var a = 1, b in (b = a + x) * (x = 2);").unwrap();
+        let ast = parser.parse_definition().unwrap();
+
+        let mut compiler = Compiler::new().unwrap();
+        ast.codegen(&mut compiler).unwrap();
+        let ir = optimized_ir(&mut compiler, &ast.proto.name);
+        assert!(!ir.contains("alloca"));
+        assert!(ir.contains("fmul double %addtmp, 2.000000e+00"));
+        assert!(compiler.variable("a").is_none());
+
+        let mut parser = Parser::new("def bad(x) (x + 1) = 2;").unwrap();
+        assert!(parser.parse_definition().is_err());
+    }
+
+    #[test]
+    fn test_compare() {
+        use crate::ast::*;
+        use crate::compile::Compiler;
+        use crate::parser::*;
+
+        let mut parser = Parser::new(
+            "def cmp(a b) (a < b) + (a > b) + (a <= b) + (a >= b) + (a == b) + (a != b);",
+        )
+        .unwrap();
+        let ast = parser.parse_definition().unwrap();
+
+        let mut compiler = Compiler::new().unwrap();
+        ast.codegen(&mut compiler).unwrap();
+        let ir = optimized_ir(&mut compiler, &ast.proto.name);
+        for predicate in ["olt", "ogt", "ole", "oge", "oeq", "une"] {
+            assert!(ir.contains(&format!("fcmp {predicate} double %a, %b")));
+        }
+
+        // comparisons bind tighter than assignment and looser than arithmetic
+        let mut parser = Parser::new("def prec(x) x = 1 + 2 < 4;").unwrap();
+        let ast = parser.parse_definition().unwrap();
+        let ExprAst::Binary(assign, _) = ast.body else {
+            panic!("expected assignment");
+        };
+        assert_eq!(assign.op, OpSymbol::Assign);
+        assert!(matches!(&assign.rhs, ExprAst::Binary(cmp, _) if cmp.op == OpSymbol::Less));
+    }
+
+    #[test]
+    fn test_logical() {
+        use crate::ast::*;
+        use crate::compile::Compiler;
+        use crate::parser::*;
+
+        let mut parser =
+            Parser::new("def guard(x) x != 0 && 1 / x > 2 || x == 0 && guard(x - 1);").unwrap();
+        let ast = parser.parse_definition().unwrap();
+
+        let mut compiler = Compiler::new().unwrap();
+        ast.codegen(&mut compiler).unwrap();
+        let ir = optimized_ir(&mut compiler, &ast.proto.name);
+        // the division only happens on the `&&` right side branch
+        let rhs_block = ir.find("andtmp.rhs:").unwrap();
+        assert!(ir.find("fdiv").unwrap() > rhs_block);
+        assert!(ir.contains("ortmp = phi double [ 1.000000e+00"));
+    }
+
+    #[test]
+    fn test_eval() {
+        use crate::ast::*;
+        use crate::compile::Compiler;
+        use crate::parser::*;
+
+        let mut compiler = Compiler::new().unwrap();
+
+        let mut parser = Parser::new("def add(a b) a + b").unwrap();
+        parser
+            .parse_definition()
+            .unwrap()
+            .codegen(&mut compiler)
+            .unwrap();
+
+        let mut parser = Parser::new("add(4, 5)").unwrap();
+        let expr = parser.parse_toplevel().unwrap();
+        assert_eq!(compiler.eval_toplevel(&expr).unwrap(), 9.0);
+
+        let mut parser = Parser::new("def twice(x) add(x, x)").unwrap();
+        parser
+            .parse_definition()
+            .unwrap()
+            .codegen(&mut compiler)
+            .unwrap();
+
+        let mut parser = Parser::new("twice(add(1, 2))").unwrap();
+        let expr = parser.parse_toplevel().unwrap();
+        assert_eq!(compiler.eval_toplevel(&expr).unwrap(), 6.0);
+
+        let mut parser = Parser::new("extern cos(x)").unwrap();
+        parser
+            .parse_extern()
+            .unwrap()
+            .codegen(&mut compiler)
+            .unwrap();
+
+        let mut parser = Parser::new("cos(0)").unwrap();
+        let expr = parser.parse_toplevel().unwrap();
+        assert_eq!(compiler.eval_toplevel(&expr).unwrap(), 1.0);
+    }
+
+    #[test]
+    fn test_redefine() {
+        use crate::ast::*;
+        use crate::compile::Compiler;
+        use crate::parser::*;
+
+        fn eval(compiler: &mut Compiler, input: &str) -> Option<f64> {
+            let mut parser = Parser::new(input).unwrap();
+            match parser.peek() {
+                crate::lex::Token::Def => {
+                    parser
+                        .parse_definition()
+                        .unwrap()
+                        .codegen(compiler)
+                        .unwrap();
+                    None
+                }
+                _ => Some(
+                    compiler
+                        .eval_toplevel(&parser.parse_toplevel().unwrap())
+                        .unwrap(),
+                ),
+            }
+        }
+
+        let mut compiler = Compiler::new().unwrap();
+
+        eval(&mut compiler, "def f(x) x + 1");
+        eval(&mut compiler, "def g(x) f(x) * 2");
+        eval(&mut compiler, "def h(x) g(x) + 1");
+        assert_eq!(eval(&mut compiler, "h(1)"), Some(5.0));
+
+        // callers of `f`, even indirect ones, use the new definition
+        eval(&mut compiler, "def f(x) x + 10");
+        assert_eq!(eval(&mut compiler, "h(1)"), Some(23.0));
+        assert_eq!(eval(&mut compiler, "f(1)"), Some(11.0));
+
+        // but not with another number of arguments while they call it
+        let mut parser = Parser::new("def f(a b) a + b").unwrap();
+        assert!(parser
+            .parse_definition()
+            .unwrap()
+            .codegen(&mut compiler)
+            .is_err());
+        assert_eq!(eval(&mut compiler, "h(1)"), Some(23.0));
+        assert_eq!(eval(&mut compiler, "f(1)"), Some(11.0));
+
+        // which is fine for a function nothing calls
+        eval(&mut compiler, "def h(a b) a * b");
+        assert_eq!(eval(&mut compiler, "h(2, 3)"), Some(6.0));
+    }
+
+    #[test]
+    fn test_program() {
+        use crate::ast::Item;
+        use crate::compile::Compiler;
+        use crate::parser::Parser;
+
+        let source = "def a() 1; def b() 2;; extern sin(x) a() + b();";
+        let items = Parser::new(source).unwrap().parse_program().unwrap();
+        assert_eq!(items.len(), 4);
+        assert!(matches!(items[1], Item::Definition(ref b) if b.proto.name == "b"));
+        assert!(matches!(items[2], Item::Extern(_)));
+        assert!(matches!(items[3], Item::Expression(_)));
+
+        let mut compiler = Compiler::new().unwrap();
+        compiler.compile_source(source).unwrap();
+        let expr = Parser::new("a() * 10 + b()")
+            .unwrap()
+            .parse_toplevel()
+            .unwrap();
+        assert_eq!(compiler.eval_toplevel(&expr).unwrap(), 12.0);
+    }
+
+    #[test]
+    fn test_error_span() {
+        use crate::compile::Compiler;
+        use crate::lex::Span;
+
+        let span = |source: &str| {
+            let mut compiler = Compiler::new().unwrap();
+            compiler.compile_source(source).unwrap_err().span().unwrap()
+        };
+
+        // the innermost expression which fails
+        let error = span("def f(x)\n  x + y * 2");
+        assert_eq!(
+            error,
+            Span {
+                start: 15,
+                end: 16,
+                line: 2,
+                column: 7
+            }
+        );
+        assert_eq!(error.to_string(), "2:7");
+        assert_eq!(span("def f(x) x\nf(1, 2)").line, 2);
+        assert_eq!(span("def f(x) x\n\n  g(1)").column, 3);
+        // lexer and parser errors
+        assert_eq!(span("def f(x) x $ 1").column, 12);
+        assert_eq!(span("def f(x x").column, 10);
+    }
+
+    #[test]
+    fn test_opt_level() {
+        use crate::compile::Compiler;
+        use crate::target::OptLevel;
+
+        let ir = |compiler: &mut Compiler| {
+            compiler.compile_source("def f(x) x * 1").unwrap();
+            optimized_ir(compiler, "f")
+        };
+
+        let mut compiler = Compiler::new().unwrap();
+        assert_eq!(compiler.pass_manager().passes(), "default<O0>");
+        assert!(ir(&mut compiler).contains("fmul"));
+
+        compiler.set_opt_level(OptLevel::Default);
+        assert_eq!(compiler.pass_manager().passes(), "default<O2>");
+        assert!(ir(&mut compiler).contains("ret double %x"));
+
+        let mut compiler = Compiler::new().unwrap();
+        compiler.set_passes("function(instcombine)").unwrap();
+        assert!(ir(&mut compiler).contains("ret double %x"));
+        assert!(compiler.set_passes("function(nope)").is_err());
+        assert_eq!(compiler.pass_manager().passes(), "function(instcombine)");
+
+        // which runs instead of the promotion of variables too
+        compiler.set_passes("verify").unwrap();
+        assert!(ir(&mut compiler).contains("alloca"));
+    }
+
+    #[test]
+    fn test_invalid_ir() {
+        use crate::compile::Compiler;
+        use crate::error::{CompileError, Error};
+        use llvm_sys::core::*;
+
+        let mut compiler = Compiler::new().unwrap();
+        // a block without terminator
+        let function = compiler.emit_proto("broken", &[]);
+        unsafe { LLVMAppendBasicBlockInContext(compiler.context, function, c"entry".as_ptr()) };
+        match compiler.verify_module() {
+            Err(Error::CompileError(CompileError::InvalidIr { function, message })) => {
+                assert_eq!(function, "broken");
+                assert!(message.contains("terminator"), "{message}");
+            }
+            result => panic!("{result:?}"),
+        }
+        unsafe { Compiler::delete_body(function) };
+        assert!(compiler.verify_module().is_ok());
+
+        // loops and branches use the values and blocks of each other
+        compiler
+            .compile_source(
+                "def f(x) var a = 0 in for i = 1, i < x in a = if a > 2 then a else a + i",
+            )
+            .unwrap();
+        unsafe {
+            let function = LLVMGetNamedFunction(compiler.module, c"f".as_ptr());
+            assert!(LLVMCountBasicBlocks(function) > 3);
+            Compiler::delete_body(function);
+            assert_eq!(LLVMCountBasicBlocks(function), 0);
+        }
+        assert!(compiler.verify_module().is_ok());
+    }
+
+    #[test]
+    fn test_failed_definition() {
+        use crate::compile::Compiler;
+        use crate::parser::Parser;
+        use crate::target::Target;
+
+        fn eval(compiler: &mut Compiler, input: &str) -> f64 {
+            let expr = Parser::new(input).unwrap().parse_toplevel().unwrap();
+            compiler.eval_toplevel(&expr).unwrap()
+        }
+        let arity = |compiler: &Compiler, name: &str| {
+            compiler
+                .functions()
+                .iter()
+                .find(|(proto, _)| proto.name == name)
+                .map(|(proto, defined)| (proto.args.len(), *defined))
+        };
+
+        let mut compiler = Compiler::new().unwrap();
+
+        // a new function is left out, even when the body fails deep in its blocks
+        assert!(compiler
+            .compile_source("def f(x) if x then (for i = 1, i < 3 in y) else x")
+            .is_err());
+        assert_eq!(arity(&compiler, "f"), None);
+        assert!(compiler.variable("x").is_none());
+        compiler.compile_source("def f(x) x + 1").unwrap();
+        assert_eq!(eval(&mut compiler, "f(1)"), 2.0);
+
+        // the earlier definition stays
+        assert!(compiler.compile_source("def f(x y) x + z").is_err());
+        assert_eq!(arity(&compiler, "f"), Some((1, true)));
+        assert_eq!(eval(&mut compiler, "f(2)"), 3.0);
+
+        // and so does a declaration
+        compiler.compile_source("extern g(x)").unwrap();
+        assert!(compiler.compile_source("def g(x) g(x) + q").is_err());
+        assert_eq!(arity(&compiler, "g"), Some((1, false)));
+        compiler.compile_source("def g(x) x * 3").unwrap();
+        assert_eq!(eval(&mut compiler, "g(f(1))"), 6.0);
+
+        // nor can a definition take another number of arguments than its declaration
+        compiler.compile_source("extern g2(x y)").unwrap();
+        assert!(compiler.compile_source("def g2(x) x").is_err());
+        assert_eq!(arity(&compiler, "g2"), Some((2, false)));
+        compiler.compile_source("def g2(x y) x - y").unwrap();
+        assert_eq!(eval(&mut compiler, "g2(5, 2)"), 3.0);
+
+        // ahead of time, everything goes in one module
+        let target = Target::host().unwrap();
+        let mut compiler = Compiler::with_target(&target, "failed").unwrap();
+        compiler.compile_source("def k(a) a").unwrap();
+        assert!(compiler.compile_source("def k(a b) a").is_err());
+        assert!(compiler.compile_source("def m(a) k(a) + b").is_err());
+        compiler.compile_source("def m(a) k(a) + 1").unwrap();
+        assert_eq!(arity(&compiler, "k"), Some((1, true)));
+        assert_eq!(
+            compiler.print_module().matches("define double @m(").count(),
+            1
+        );
+        assert!(compiler.verify_module().is_ok());
+    }
 }
@@ -0,0 +1,302 @@
+use std::{path::Path, ptr::null_mut};
+
+use llvm_sys::{
+    core::{
+        LLVMAddModuleFlag, LLVMConstInt, LLVMGetInsertBlock, LLVMInt32TypeInContext,
+        LLVMSetCurrentDebugLocation2, LLVMSetIsNewDbgInfoFormat, LLVMValueAsMetadata,
+    },
+    debuginfo::{
+        LLVMCreateDIBuilder, LLVMDIBuilderCreateBasicType, LLVMDIBuilderCreateCompileUnit,
+        LLVMDIBuilderCreateDebugLocation, LLVMDIBuilderCreateExpression, LLVMDIBuilderCreateFile,
+        LLVMDIBuilderCreateFunction, LLVMDIBuilderCreateParameterVariable,
+        LLVMDIBuilderCreateSubroutineType, LLVMDIBuilderFinalize, LLVMDIBuilderFinalizeSubprogram,
+        LLVMDIBuilderInsertDeclareRecordAtEnd, LLVMDIFlagPrototyped, LLVMDIFlagZero,
+        LLVMDWARFEmissionKind, LLVMDWARFSourceLanguage, LLVMDebugMetadataVersion,
+        LLVMDisposeDIBuilder, LLVMSetSubprogram,
+    },
+    prelude::{
+        LLVMBuilderRef, LLVMContextRef, LLVMDIBuilderRef, LLVMMetadataRef, LLVMModuleRef,
+        LLVMValueRef,
+    },
+    LLVMModuleFlagBehavior,
+};
+
+use crate::{ast::PrototypeAst, lex::Span};
+
+/// `DW_ATE_float`
+const DW_ATE_FLOAT: u32 = 0x04;
+
+/// Source file described by the debug information
+#[derive(Debug, Clone)]
+pub struct SourceFile {
+    pub name: String,
+    pub directory: String,
+}
+
+impl SourceFile {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        let path = path.as_ref();
+        let directory = match path.parent() {
+            Some(parent) if parent.as_os_str().is_empty() => ".".into(),
+            Some(parent) => parent.to_string_lossy().into_owned(),
+            None => ".".into(),
+        };
+
+        Self {
+            name: path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            directory,
+        }
+    }
+}
+
+/// DWARF of one module: a compile unit for the source file and a subprogram
+/// for each function
+pub(crate) struct DebugInfo {
+    context: LLVMContextRef,
+    builder: LLVMDIBuilderRef,
+    file: LLVMMetadataRef,
+    double_type: LLVMMetadataRef,
+    /// subprogram of the function being compiled
+    scope: Option<LLVMMetadataRef>,
+}
+
+impl DebugInfo {
+    pub(crate) fn new(context: LLVMContextRef, module: LLVMModuleRef, source: &SourceFile) -> Self {
+        unsafe {
+            LLVMSetIsNewDbgInfoFormat(module, 1);
+            let int_type = LLVMInt32TypeInContext(context);
+            for (key, value) in [
+                ("Debug Info Version", LLVMDebugMetadataVersion()),
+                ("Dwarf Version", 4),
+            ] {
+                let value = LLVMValueAsMetadata(LLVMConstInt(int_type, value as u64, 0));
+                LLVMAddModuleFlag(
+                    module,
+                    LLVMModuleFlagBehavior::LLVMModuleFlagBehaviorWarning,
+                    key.as_ptr() as *const _,
+                    key.len(),
+                    value,
+                );
+            }
+
+            let builder = LLVMCreateDIBuilder(module);
+            let file = LLVMDIBuilderCreateFile(
+                builder,
+                source.name.as_ptr() as *const _,
+                source.name.len(),
+                source.directory.as_ptr() as *const _,
+                source.directory.len(),
+            );
+
+            let producer = "kaleidoscope";
+            LLVMDIBuilderCreateCompileUnit(
+                builder,
+                LLVMDWARFSourceLanguage::LLVMDWARFSourceLanguageC,
+                file,
+                producer.as_ptr() as *const _,
+                producer.len(),
+                0,
+                null_mut(),
+                0,
+                0,
+                null_mut(),
+                0,
+                LLVMDWARFEmissionKind::LLVMDWARFEmissionKindFull,
+                0,
+                0,
+                0,
+                null_mut(),
+                0,
+                null_mut(),
+                0,
+            );
+
+            let name = "double";
+            let double_type = LLVMDIBuilderCreateBasicType(
+                builder,
+                name.as_ptr() as *const _,
+                name.len(),
+                64,
+                DW_ATE_FLOAT,
+                LLVMDIFlagZero,
+            );
+
+            Self {
+                context,
+                builder,
+                file,
+                double_type,
+                scope: None,
+            }
+        }
+    }
+
+    /// Attach a subprogram to `function`, which is named `name` and declared by `proto`.
+    /// It is the scope of the locations until [`DebugInfo::finish_function`].
+    pub(crate) fn create_function(
+        &mut self,
+        function: LLVMValueRef,
+        name: &str,
+        proto: &PrototypeAst,
+        span: Span,
+    ) {
+        unsafe {
+            let mut types = vec![self.double_type; proto.args.len() + 1];
+            let function_type = LLVMDIBuilderCreateSubroutineType(
+                self.builder,
+                self.file,
+                types.as_mut_ptr(),
+                types.len() as u32,
+                LLVMDIFlagZero,
+            );
+
+            let subprogram = LLVMDIBuilderCreateFunction(
+                self.builder,
+                self.file,
+                name.as_ptr() as *const _,
+                name.len(),
+                name.as_ptr() as *const _,
+                name.len(),
+                self.file,
+                span.line,
+                function_type,
+                0,
+                1,
+                proto.span.line,
+                LLVMDIFlagPrototyped,
+                0,
+            );
+            LLVMSetSubprogram(function, subprogram);
+            self.scope = Some(subprogram);
+        }
+    }
+
+    /// Describe the parameter `arg_no` (starting at 1) of the current function, stored in `alloca`
+    pub(crate) fn declare_parameter(
+        &self,
+        builder: LLVMBuilderRef,
+        alloca: LLVMValueRef,
+        name: &str,
+        arg_no: u32,
+        span: Span,
+    ) {
+        let Some(scope) = self.scope else {
+            return;
+        };
+
+        unsafe {
+            let variable = LLVMDIBuilderCreateParameterVariable(
+                self.builder,
+                scope,
+                name.as_ptr() as *const _,
+                name.len(),
+                arg_no,
+                self.file,
+                span.line,
+                self.double_type,
+                1,
+                LLVMDIFlagZero,
+            );
+
+            LLVMDIBuilderInsertDeclareRecordAtEnd(
+                self.builder,
+                alloca,
+                variable,
+                LLVMDIBuilderCreateExpression(self.builder, null_mut(), 0),
+                self.location(span),
+                LLVMGetInsertBlock(builder),
+            );
+        }
+    }
+
+    /// Location of `span` in the current function
+    pub(crate) fn location(&self, span: Span) -> LLVMMetadataRef {
+        match self.scope {
+            Some(scope) => unsafe {
+                LLVMDIBuilderCreateDebugLocation(
+                    self.context,
+                    span.line,
+                    span.column,
+                    scope,
+                    null_mut(),
+                )
+            },
+            None => null_mut(),
+        }
+    }
+
+    /// Give the location of `span` to the next instructions built by `builder`
+    pub(crate) fn set_location(&self, builder: LLVMBuilderRef, span: Span) {
+        unsafe { LLVMSetCurrentDebugLocation2(builder, self.location(span)) }
+    }
+
+    pub(crate) fn finish_function(&mut self) {
+        if let Some(scope) = self.scope.take() {
+            unsafe { LLVMDIBuilderFinalizeSubprogram(self.builder, scope) }
+        }
+    }
+
+    /// Resolve the debug information, before the module is compiled or handed to the jit
+    pub(crate) fn finalize(&self) {
+        unsafe { LLVMDIBuilderFinalize(self.builder) }
+    }
+}
+
+impl Drop for DebugInfo {
+    fn drop(&mut self) {
+        unsafe { LLVMDisposeDIBuilder(self.builder) }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_debug_info() {
+        use crate::compile::Compiler;
+        use crate::parser::Parser;
+        use crate::target::{OptLevel, OutputKind, Target};
+
+        let target = Target::host().unwrap();
+        let mut compiler = Compiler::with_target(&target, "fib").unwrap();
+        compiler.enable_debug_info("examples/fib.ks");
+        compiler.set_opt_level(OptLevel::Default);
+        compiler
+            .compile_source(
+                "# fibonacci\ndef fib(n)\n  if n < 2 then n else fib(n - 1) + fib(n - 2);\nfib(10)",
+            )
+            .unwrap();
+
+        let dir = std::env::temp_dir().join(format!("kaleidoscope-debug-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        compiler
+            .write_to_file(&target, dir.join("fib.o"), OutputKind::Object)
+            .unwrap();
+        compiler
+            .write_to_file(&target, dir.join("fib.ll"), OutputKind::LlvmIr)
+            .unwrap();
+        let ir = std::fs::read_to_string(dir.join("fib.ll")).unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+
+        assert!(ir.contains(r#"!DIFile(filename: "fib.ks", directory: "examples")"#));
+        assert!(ir.contains(r#"distinct !DISubprogram(name: "fib", linkageName: "fib", scope: !"#));
+        assert!(ir.contains(r#"name: "n", arg: 1"#));
+        // the `if` starts on line 3, column 3
+        assert!(ir.contains("!DILocation(line: 3, column: 3"));
+        assert!(ir.contains(r#"!DISubprogram(name: "__anon_expr.1""#));
+        // optimized, the parameter lives in a register
+        assert!(!ir.contains("alloca"));
+        assert!(ir.contains("call void @llvm.dbg.value(metadata double %n"));
+
+        // with the jit, each module gets its own compile unit
+        let mut compiler = Compiler::new().unwrap();
+        compiler.enable_debug_info("fib.ks");
+        compiler
+            .compile_source("def fib(n) if n < 2 then n else fib(n - 1) + fib(n - 2)")
+            .unwrap();
+        let expr = Parser::new("fib(10)").unwrap().parse_toplevel().unwrap();
+        assert_eq!(compiler.eval_toplevel(&expr).unwrap(), 55.0);
+    }
+}
@@ -0,0 +1,402 @@
+use std::{fmt::Write, io::IsTerminal};
+
+use crate::{
+    error::{CompileError, Error, LexError, ParserError},
+    lex::Span,
+};
+
+const RESET: &str = "\x1b[0m";
+const BOLD: &str = "\x1b[1m";
+const RED: &str = "\x1b[1;31m";
+const YELLOW: &str = "\x1b[1;33m";
+const BLUE: &str = "\x1b[1;34m";
+const CYAN: &str = "\x1b[1;36m";
+
+/// Whether diagnostics printed to `stream` (e.g. stderr) should be coloured: `stream`
+/// is a terminal and `NO_COLOR` is not set
+pub fn color_enabled(stream: &impl IsTerminal) -> bool {
+    stream.is_terminal() && std::env::var_os("NO_COLOR").is_none()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Error,
+    Warning,
+}
+
+impl Level {
+    fn name(&self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warning => "warning",
+        }
+    }
+
+    fn color(&self) -> &'static str {
+        match self {
+            Level::Error => RED,
+            Level::Warning => YELLOW,
+        }
+    }
+}
+
+/// A message attached to a span of the source
+#[derive(Debug, Clone)]
+pub struct Label {
+    pub span: Span,
+    pub message: String,
+    /// the primary label is underlined with `^`, secondary ones with `-`
+    pub primary: bool,
+}
+
+/// An error or a warning, rendered like rustc does:
+///
+/// ```text
+/// error[E0303]: this function takes 1 arguments but 2 were supplied
+///  --> fib.ks:2:1
+///   |
+/// 1 | def f(x) x
+///   |     ---- function declared here with 1 params
+/// 2 | f(1, 2)
+///   | ^^^^^^^ expected 1 arguments, found 2
+/// ```
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub level: Level,
+    /// e.g. `E0301`, see [`Diagnostic::from`] for the codes of [`Error`]
+    pub code: Option<&'static str>,
+    pub message: String,
+    pub labels: Vec<Label>,
+    pub notes: Vec<String>,
+    pub helps: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn new<S: Into<String>>(level: Level, message: S) -> Self {
+        Self {
+            level,
+            code: None,
+            message: message.into(),
+            labels: Vec::new(),
+            notes: Vec::new(),
+            helps: Vec::new(),
+        }
+    }
+
+    pub fn error<S: Into<String>>(message: S) -> Self {
+        Self::new(Level::Error, message)
+    }
+
+    pub fn warning<S: Into<String>>(message: S) -> Self {
+        Self::new(Level::Warning, message)
+    }
+
+    pub fn code(mut self, code: &'static str) -> Self {
+        self.code = Some(code);
+        self
+    }
+
+    pub fn primary<S: Into<String>>(mut self, span: Span, message: S) -> Self {
+        self.labels.push(Label {
+            span,
+            message: message.into(),
+            primary: true,
+        });
+        self
+    }
+
+    pub fn secondary<S: Into<String>>(mut self, span: Span, message: S) -> Self {
+        self.labels.push(Label {
+            span,
+            message: message.into(),
+            primary: false,
+        });
+        self
+    }
+
+    pub fn note<S: Into<String>>(mut self, note: S) -> Self {
+        self.notes.push(note.into());
+        self
+    }
+
+    pub fn help<S: Into<String>>(mut self, help: S) -> Self {
+        self.helps.push(help.into());
+        self
+    }
+
+    /// Render the diagnostic for `source`, which is named `name` (e.g. its path).
+    ///
+    /// Labels whose span doesn't belong to `source` are left out, as well as the
+    /// `-->` location if there is no label left. `color` adds ANSI escape codes,
+    /// see [`color_enabled`].
+    pub fn render(&self, name: &str, source: &str, color: bool) -> String {
+        let paint = |style: &str, text: &str| {
+            if color {
+                format!("{style}{text}{RESET}")
+            } else {
+                text.to_string()
+            }
+        };
+
+        let mut labels = self
+            .labels
+            .iter()
+            .filter(|label| locate(source, label.span).is_some())
+            .collect::<Vec<_>>();
+        labels.sort_by_key(|label| (label.span.line, label.span.start));
+
+        let mut out = String::new();
+        let mut title = self.level.name().to_string();
+        if let Some(code) = self.code {
+            title.push_str(&format!("[{code}]"));
+        }
+        let _ = writeln!(
+            out,
+            "{}{}",
+            paint(self.level.color(), &title),
+            paint(BOLD, &format!(": {}", self.message))
+        );
+
+        let width = labels
+            .iter()
+            .map(|label| label.span.line.to_string().len())
+            .max()
+            .unwrap_or(0);
+        let pad = " ".repeat(width);
+        let gutter = paint(BLUE, &format!("{pad} |"));
+
+        if let Some(main) = labels.iter().find(|label| label.primary).or(labels.first()) {
+            let _ = writeln!(out, "{pad}{} {name}:{}", paint(BLUE, "-->"), main.span);
+            let _ = writeln!(out, "{gutter}");
+
+            let mut last_line = None;
+            for label in &labels {
+                let (line_start, line_end) = locate(source, label.span).unwrap();
+                let line = label.span.line;
+                if last_line != Some(line) {
+                    if matches!(last_line, Some(last) if line > last + 1) {
+                        let _ = writeln!(out, "{}", paint(BLUE, "..."));
+                    }
+                    let text = source[line_start..line_end].trim_end_matches('\r');
+                    let number = format!("{line:>width$} |");
+                    let _ = writeln!(out, "{} {text}", paint(BLUE, &number));
+                    last_line = Some(line);
+                }
+
+                let indent = source[line_start..label.span.start]
+                    .chars()
+                    .map(|ch| if ch == '\t' { '\t' } else { ' ' })
+                    .collect::<String>();
+                let end = label.span.end.clamp(label.span.start, line_end);
+                let length = source[label.span.start..end].chars().count().max(1);
+                let (mark, style) = if label.primary {
+                    ("^", self.level.color())
+                } else {
+                    ("-", BLUE)
+                };
+                let mut underline = mark.repeat(length);
+                if !label.message.is_empty() {
+                    underline.push(' ');
+                    underline.push_str(&label.message);
+                }
+                let _ = writeln!(out, "{gutter} {indent}{}", paint(style, &underline));
+            }
+        }
+
+        if !self.notes.is_empty() || !self.helps.is_empty() {
+            if !labels.is_empty() {
+                let _ = writeln!(out, "{gutter}");
+            }
+            for note in &self.notes {
+                let _ = writeln!(out, "{pad} {} {note}", paint(BOLD, "= note:"));
+            }
+            for help in &self.helps {
+                let _ = writeln!(out, "{pad} {} {help}", paint(CYAN, "= help:"));
+            }
+        }
+
+        out
+    }
+}
+
+/// Byte range of the line of `span` in `source`, `None` if `span` is not in `source`
+fn locate(source: &str, span: Span) -> Option<(usize, usize)> {
+    if span.line == 0
+        || span.start > span.end
+        || span.end > source.len()
+        || !source.is_char_boundary(span.start)
+        || !source.is_char_boundary(span.end)
+    {
+        return None;
+    }
+
+    let before = &source[..span.start];
+    if before.matches('\n').count() + 1 != span.line as usize {
+        return None;
+    }
+    let line_start = before.rfind('\n').map_or(0, |newline| newline + 1);
+    let line_end = source[span.start..]
+        .find('\n')
+        .map_or(source.len(), |newline| span.start + newline);
+
+    Some((line_start, line_end))
+}
+
+/// The codes are `E01xx` for the lexer, `E02xx` for the parser and `E03xx` for the compiler.
+impl From<&Error> for Diagnostic {
+    fn from(error: &Error) -> Self {
+        let diagnostic = Diagnostic::error(error.to_string());
+        match error {
+            Error::LexError(error) => match error {
+                LexError::UnsupportSymbol(_, span) => {
+                    diagnostic.code("E0101").primary(*span, "unknown character")
+                }
+                LexError::UnclosedString(quote, span) => diagnostic
+                    .code("E0102")
+                    .primary(*span, "string starts here")
+                    .help(format!("close the string with `{quote}`")),
+                LexError::ParseFloatError(_, span) => {
+                    diagnostic.code("E0103").primary(*span, "invalid number")
+                }
+                LexError::IoError(_) => diagnostic,
+            },
+            Error::ParserError(error) => match error {
+                ParserError::ExpectedFunctionName(span) => diagnostic
+                    .code("E0201")
+                    .primary(*span, "expected a name")
+                    .help("a prototype looks like `name(arg1 arg2)`"),
+                ParserError::ParseOpSymbolError(_, span) => {
+                    diagnostic.code("E0202").primary(*span, "not an operator")
+                }
+                ParserError::UnexpectedToken(_, span) => {
+                    diagnostic.code("E0203").primary(*span, "unexpected token")
+                }
+                ParserError::SyntaxError(_, span) => diagnostic.code("E0204").primary(*span, ""),
+            },
+            Error::CompileError(error) => match error {
+                CompileError::UnknowVariableName(_, span) => diagnostic
+                    .code("E0301")
+                    .primary(*span, "not found in this scope")
+                    .help("declare it with `var` or as a parameter of the function"),
+                CompileError::UnknowFunction(name, span) => diagnostic
+                    .code("E0302")
+                    .primary(*span, "not found")
+                    .help(format!(
+                        "define it with `def {name}(...)` or declare it with `extern {name}(...)`"
+                    )),
+                CompileError::IncorrectArguments {
+                    expect,
+                    get,
+                    span,
+                    declared,
+                } => {
+                    let diagnostic = diagnostic
+                        .code("E0303")
+                        .primary(*span, format!("expected {expect} arguments, found {get}"));
+                    match declared {
+                        Some(declared) => diagnostic.secondary(
+                            *declared,
+                            format!("function declared here with {expect} params"),
+                        ),
+                        None => diagnostic,
+                    }
+                }
+                CompileError::FunctionRedifined(span) => diagnostic
+                    .code("E0304")
+                    .primary(*span, "redefined here")
+                    .note("a function can only be defined once in a module"),
+                CompileError::InvalidAssignTarget(span) => diagnostic
+                    .code("E0305")
+                    .primary(*span, "cannot assign to this expression")
+                    .help("only variables can be assigned, e.g. `x = 1`"),
+                CompileError::InvalidExpression(span) => diagnostic
+                    .code("E0308")
+                    .primary(*span, "failed to parse")
+                    .note("its syntax error is reported separately"),
+                CompileError::UnsupportedBinaryOperator(_, span) => {
+                    diagnostic.code("E0306").primary(*span, "")
+                }
+                CompileError::InvalidIr { .. } => diagnostic
+                    .code("E0309")
+                    .note("the code generated for the function is wrong, this is a bug"),
+                CompileError::MissingMain => diagnostic
+                    .code("E0307")
+                    .help("add `def main()` or a top-level expression"),
+                _ => diagnostic,
+            },
+        }
+    }
+}
+
+impl From<Error> for Diagnostic {
+    fn from(error: Error) -> Self {
+        Self::from(&error)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Diagnostic;
+    use crate::compile::Compiler;
+
+    #[test]
+    fn test_render() {
+        let source = "def f(x) x\n\nf(1, 2)";
+        let mut compiler = Compiler::new().unwrap();
+        let error = compiler.compile_source(source).unwrap_err();
+
+        let rendered = Diagnostic::from(&error).render("test.ks", source, false);
+        assert_eq!(
+            rendered,
+            "error[E0303]: this function takes 1 arguments but 2 were supplied\n \
+             --> test.ks:3:1\n  \
+             |\n\
+             1 | def f(x) x\n  \
+             |     ---- function declared here with 1 params\n\
+             ...\n\
+             3 | f(1, 2)\n  \
+             | ^^^^^^^ expected 1 arguments, found 2\n"
+        );
+
+        let source = "def f(x)\n\ty + x";
+        let error = Compiler::new().unwrap().compile_source(source).unwrap_err();
+        let rendered = Diagnostic::from(&error).render("test.ks", source, false);
+        assert_eq!(
+            rendered,
+            "error[E0301]: cannot find variable `y` in this scope\n \
+             --> test.ks:2:2\n  \
+             |\n\
+             2 | \ty + x\n  \
+             | \t^ not found in this scope\n  \
+             |\n  \
+             = help: declare it with `var` or as a parameter of the function\n"
+        );
+
+        // spans of another source are left out
+        let rendered = Diagnostic::from(&error).render("other.ks", "1", true);
+        assert!(rendered.starts_with("\x1b[1;31merror[E0301]\x1b[0m"));
+        assert!(!rendered.contains("-->"));
+        assert!(rendered.contains("= help:"));
+    }
+
+    #[test]
+    fn test_parse_all() {
+        use crate::parser::Parser;
+
+        let source = "def f(x) x +\n\
+                      def g(x) x * 2;\n\
+                      extern (x);\n\
+                      g(1, );\n\
+                      f(g(2))";
+        let (_, errors) = Parser::new(source).unwrap().parse_all();
+
+        let diagnostics = errors
+            .iter()
+            .map(|error| Diagnostic::from(error).render("test.ks", source, false))
+            .collect::<Vec<_>>();
+        assert_eq!(diagnostics.len(), 3);
+        assert!(diagnostics[0].contains(" --> test.ks:2:1\n"));
+        assert!(diagnostics[1].contains(" --> test.ks:3:8\n"));
+        assert!(diagnostics[2].contains(" --> test.ks:4:6\n"));
+    }
+}
@@ -0,0 +1,605 @@
+// the line editing needs a unix terminal, elsewhere lines are read as they come
+#![cfg_attr(not(unix), allow(dead_code))]
+
+use std::{
+    env,
+    fs::{self, OpenOptions},
+    io::{self, BufRead, Read, Write},
+    path::{Path, PathBuf},
+};
+
+/// Entries kept in the history file
+const MAX_HISTORY: usize = 1000;
+
+/// Name of the history file in the home directory
+const HISTORY_FILE: &str = ".kaleidoscope_history";
+
+/// Line editor of the interactive session, with a history kept between sessions.
+///
+/// On a terminal the line can be edited with the arrow keys and the emacs bindings
+/// (`ctrl-a`, `ctrl-e`, `ctrl-k`, `ctrl-u`, `ctrl-w`...), `up`/`down` browse the history,
+/// `ctrl-r` searches it and `tab` completes the word before the cursor.
+/// Otherwise, or on other systems than unix, lines are read as they come.
+#[derive(Debug, Default)]
+pub struct Editor {
+    history: Vec<String>,
+    /// where the history is appended to, see [`Editor::with_history_file`]
+    history_file: Option<PathBuf>,
+}
+
+/// Outcome of `ctrl-r`
+enum Search {
+    /// `enter` on a match, the line is done
+    Accept(String),
+    /// any other key, which is then applied to the match
+    Edit(String, Key),
+    Cancel,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Key {
+    Char(char),
+    /// a control character, e.g. `Ctrl('a')` for `ctrl-a`
+    Ctrl(char),
+    /// `alt-<char>`, or `escape` followed by the character
+    Alt(char),
+    Enter,
+    Tab,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+    Unknown,
+}
+
+/// Line being edited
+#[derive(Debug, Default)]
+struct Line {
+    chars: Vec<char>,
+    /// index in `chars`
+    cursor: usize,
+}
+
+impl Editor {
+    /// Editor without history file
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Editor which loads its history from `path` and appends the new entries to it
+    pub fn with_history_file<P: AsRef<Path>>(path: P) -> Self {
+        let path = path.as_ref().to_path_buf();
+        let mut history = fs::read_to_string(&path)
+            .map(|history| history.lines().map(String::from).collect::<Vec<_>>())
+            .unwrap_or_default();
+        if history.len() > MAX_HISTORY {
+            history.drain(..history.len() - MAX_HISTORY);
+            let _ = fs::write(&path, history.join("\n") + "\n");
+        }
+
+        Self {
+            history,
+            history_file: Some(path),
+        }
+    }
+
+    /// `~/.kaleidoscope_history`
+    pub fn default_history_file() -> Option<PathBuf> {
+        env::var_os("HOME")
+            .filter(|home| !home.is_empty())
+            .map(|home| PathBuf::from(home).join(HISTORY_FILE))
+    }
+
+    pub fn history(&self) -> &[String] {
+        &self.history
+    }
+
+    /// Remember `line`, unless it is blank or repeats the last entry.
+    ///
+    /// Failing to write the history file is not an error, the entry is still kept for
+    /// this session.
+    pub fn add_history(&mut self, line: &str) {
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.trim().is_empty() || self.history.last().is_some_and(|last| last == line) {
+            return;
+        }
+
+        self.history.push(line.to_string());
+        if self.history.len() > MAX_HISTORY {
+            self.history.remove(0);
+        }
+        if let Some(path) = &self.history_file {
+            let _ = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| writeln!(file, "{line}"));
+        }
+    }
+
+    /// Read a line, without its line break, or `None` at the end of the input.
+    ///
+    /// `complete` gives the candidates for the word before the cursor on `tab`.
+    /// `ctrl-c` discards the line with an [`io::ErrorKind::Interrupted`] error.
+    pub fn read_line(
+        &self,
+        prompt: &str,
+        complete: &dyn Fn(&str) -> Vec<String>,
+    ) -> io::Result<Option<String>> {
+        let stdin = io::stdin();
+        let mut stdout = io::stdout();
+        #[cfg(unix)]
+        {
+            use std::io::IsTerminal;
+            if stdin.is_terminal() && stdout.is_terminal() {
+                let _raw = RawMode::enable()?;
+                return self.edit(prompt, &mut stdin.lock(), &mut stdout, complete);
+            }
+        }
+
+        #[cfg(not(unix))]
+        let _ = complete;
+        write!(stdout, "{prompt}")?;
+        stdout.flush()?;
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line)? {
+            0 => Ok(None),
+            _ => Ok(Some(line.trim_end_matches(['\r', '\n']).to_string())),
+        }
+    }
+
+    /// Edit a line with the keys read from `input`, the line is drawn on `output`
+    fn edit<R: Read, W: Write>(
+        &self,
+        prompt: &str,
+        input: &mut R,
+        output: &mut W,
+        complete: &dyn Fn(&str) -> Vec<String>,
+    ) -> io::Result<Option<String>> {
+        let mut line = Line::default();
+        // entry of the history being shown, `history.len()` for the new line
+        let mut index = self.history.len();
+        let mut draft = String::new();
+        // key which ended a search
+        let mut pending = None;
+
+        loop {
+            line.draw(prompt, output)?;
+            let Some(key) = pending
+                .take()
+                .map_or_else(|| read_key(input), |key| Ok(Some(key)))?
+            else {
+                write!(output, "\r\n")?;
+                return Ok((!line.chars.is_empty()).then(|| line.to_string()));
+            };
+
+            match key {
+                Key::Enter => {
+                    write!(output, "\r\n")?;
+                    return Ok(Some(line.to_string()));
+                }
+                Key::Ctrl('d') if line.chars.is_empty() => {
+                    write!(output, "\r\n")?;
+                    return Ok(None);
+                }
+                Key::Ctrl('c') => {
+                    write!(output, "^C\r\n")?;
+                    return Err(io::ErrorKind::Interrupted.into());
+                }
+                Key::Char(c) => line.insert(c),
+                Key::Backspace if line.cursor > 0 => {
+                    line.cursor -= 1;
+                    line.chars.remove(line.cursor);
+                }
+                Key::Delete | Key::Ctrl('d') if line.cursor < line.chars.len() => {
+                    line.chars.remove(line.cursor);
+                }
+                Key::Left | Key::Ctrl('b') => line.cursor = line.cursor.saturating_sub(1),
+                Key::Right | Key::Ctrl('f') => {
+                    line.cursor = (line.cursor + 1).min(line.chars.len())
+                }
+                Key::Home | Key::Ctrl('a') => line.cursor = 0,
+                Key::End | Key::Ctrl('e') => line.cursor = line.chars.len(),
+                Key::Alt('b') => line.cursor = line.word_start(),
+                Key::Alt('f') => line.cursor = line.word_end(),
+                Key::Ctrl('k') => line.chars.truncate(line.cursor),
+                Key::Ctrl('u') => {
+                    line.chars.drain(..line.cursor);
+                    line.cursor = 0;
+                }
+                Key::Ctrl('w') => {
+                    let start = line.word_start();
+                    line.chars.drain(start..line.cursor);
+                    line.cursor = start;
+                }
+                Key::Ctrl('l') => write!(output, "\x1b[H\x1b[2J")?,
+                Key::Up | Key::Ctrl('p') if index > 0 => {
+                    if index == self.history.len() {
+                        draft = line.to_string();
+                    }
+                    index -= 1;
+                    line = Line::from(self.history[index].as_str());
+                }
+                Key::Down | Key::Ctrl('n') if index < self.history.len() => {
+                    index += 1;
+                    line = match self.history.get(index) {
+                        Some(entry) => Line::from(entry.as_str()),
+                        None => Line::from(draft.as_str()),
+                    };
+                }
+                Key::Ctrl('r') => match self.search(input, output)? {
+                    Search::Accept(entry) => {
+                        Line::from(entry.as_str()).draw(prompt, output)?;
+                        write!(output, "\r\n")?;
+                        return Ok(Some(entry));
+                    }
+                    Search::Edit(entry, key) => {
+                        line = Line::from(entry.as_str());
+                        pending = Some(key);
+                    }
+                    Search::Cancel => {}
+                },
+                Key::Tab => line.complete(prompt, output, complete)?,
+                _ => {}
+            }
+        }
+    }
+
+    /// Search the history backwards for the entries containing the typed text
+    fn search<R: Read, W: Write>(&self, input: &mut R, output: &mut W) -> io::Result<Search> {
+        let mut query = String::new();
+        let mut found = self.find(&query, self.history.len());
+
+        loop {
+            let entry = found.map_or("", |i| self.history[i].as_str());
+            let failing = if found.is_none() { "failing " } else { "" };
+            write!(
+                output,
+                "\r({failing}reverse-i-search)`{query}': {entry}\x1b[K"
+            )?;
+            output.flush()?;
+
+            match read_key(input)? {
+                Some(Key::Char(c)) => {
+                    query.push(c);
+                    // the current entry may still match
+                    found = self.find(&query, found.map_or(self.history.len(), |i| i + 1));
+                }
+                Some(Key::Backspace) => {
+                    query.pop();
+                    found = self.find(&query, self.history.len());
+                }
+                Some(Key::Ctrl('r')) => {
+                    if let Some(older) = found.and_then(|i| self.find(&query, i)) {
+                        found = Some(older);
+                    }
+                }
+                None | Some(Key::Ctrl('c')) | Some(Key::Ctrl('g')) => return Ok(Search::Cancel),
+                Some(_) if found.is_none() => return Ok(Search::Cancel),
+                Some(Key::Enter) => return Ok(Search::Accept(entry.to_string())),
+                Some(key) => return Ok(Search::Edit(entry.to_string(), key)),
+            }
+        }
+    }
+
+    /// Last entry before `before` containing `query`
+    fn find(&self, query: &str, before: usize) -> Option<usize> {
+        self.history[..before]
+            .iter()
+            .rposition(|entry| entry.contains(query))
+    }
+}
+
+impl Line {
+    fn insert(&mut self, c: char) {
+        self.chars.insert(self.cursor, c);
+        self.cursor += 1;
+    }
+
+    /// Start of the word before the cursor
+    fn word_start(&self) -> usize {
+        let before = &self.chars[..self.cursor];
+        let end = before
+            .iter()
+            .rposition(|c| is_word(*c))
+            .map_or(0, |i| i + 1);
+        before[..end]
+            .iter()
+            .rposition(|c| !is_word(*c))
+            .map_or(0, |i| i + 1)
+    }
+
+    /// End of the word after the cursor
+    fn word_end(&self) -> usize {
+        let after = &self.chars[self.cursor..];
+        let start = after
+            .iter()
+            .position(|c| is_word(*c))
+            .unwrap_or(after.len());
+        let end = after[start..]
+            .iter()
+            .position(|c| !is_word(*c))
+            .map_or(after.len(), |i| start + i);
+        self.cursor + end
+    }
+
+    /// Replace the word before the cursor with the longest prefix common to its
+    /// completions, or list them when there is nothing to add
+    fn complete<W: Write>(
+        &mut self,
+        prompt: &str,
+        output: &mut W,
+        complete: &dyn Fn(&str) -> Vec<String>,
+    ) -> io::Result<()> {
+        let start = self.chars[..self.cursor]
+            .iter()
+            .rposition(|c| !is_word(*c))
+            .map_or(0, |i| i + 1);
+        let word = self.chars[start..self.cursor].iter().collect::<String>();
+        let candidates = complete(&word)
+            .into_iter()
+            .filter(|candidate| candidate.starts_with(&word))
+            .collect::<Vec<_>>();
+
+        let Some(first) = candidates.first() else {
+            // bell
+            return write!(output, "\x07");
+        };
+        let common = candidates.iter().fold(first.as_str(), |common, candidate| {
+            let len = common
+                .char_indices()
+                .zip(candidate.chars())
+                .find(|((_, a), b)| a != b)
+                .map_or(common.len().min(candidate.len()), |((i, _), _)| i);
+            &common[..len]
+        });
+
+        if common.len() > word.len() {
+            common[word.len()..].chars().for_each(|c| self.insert(c));
+        } else if candidates.len() > 1 {
+            write!(output, "\r\n{}\r\n", candidates.join("  "))?;
+            self.draw(prompt, output)?;
+        }
+        Ok(())
+    }
+
+    /// Redraw the line after `prompt` and put the terminal cursor on the cursor
+    fn draw<W: Write>(&self, prompt: &str, output: &mut W) -> io::Result<()> {
+        write!(output, "\r{prompt}{self}\x1b[K\r")?;
+        let column = prompt.chars().count() + self.cursor;
+        if column > 0 {
+            write!(output, "\x1b[{column}C")?;
+        }
+        output.flush()
+    }
+}
+
+impl From<&str> for Line {
+    fn from(value: &str) -> Self {
+        let chars = value.chars().collect::<Vec<_>>();
+        Self {
+            cursor: chars.len(),
+            chars,
+        }
+    }
+}
+
+impl std::fmt::Display for Line {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.chars.iter().try_for_each(|c| write!(f, "{c}"))
+    }
+}
+
+/// Characters of the words moved over by `alt-b`/`alt-f`, removed by `ctrl-w` and completed
+fn is_word(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == ':'
+}
+
+fn read_byte<R: Read>(input: &mut R) -> io::Result<Option<u8>> {
+    let mut byte = [0];
+    loop {
+        match input.read(&mut byte) {
+            Ok(0) => return Ok(None),
+            Ok(_) => return Ok(Some(byte[0])),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Next key pressed, `None` at the end of the input
+fn read_key<R: Read>(input: &mut R) -> io::Result<Option<Key>> {
+    let Some(byte) = read_byte(input)? else {
+        return Ok(None);
+    };
+
+    let key = match byte {
+        b'\r' | b'\n' => Key::Enter,
+        b'\t' => Key::Tab,
+        0x08 | 0x7f => Key::Backspace,
+        0x1b => return read_escape(input),
+        0x01..=0x1a => Key::Ctrl((b'a' + byte - 1) as char),
+        0x00..=0x1f => Key::Unknown,
+        0x20..=0x7e => Key::Char(byte as char),
+        _ => {
+            // the rest of an utf-8 character
+            let len = match byte {
+                0xc0..=0xdf => 2,
+                0xe0..=0xef => 3,
+                0xf0..=0xf7 => 4,
+                _ => return Ok(Some(Key::Unknown)),
+            };
+            let mut bytes = vec![byte];
+            for _ in 1..len {
+                match read_byte(input)? {
+                    Some(byte) => bytes.push(byte),
+                    None => return Ok(None),
+                }
+            }
+            match std::str::from_utf8(&bytes)
+                .ok()
+                .and_then(|s| s.chars().next())
+            {
+                Some(c) => Key::Char(c),
+                None => Key::Unknown,
+            }
+        }
+    };
+    Ok(Some(key))
+}
+
+/// Key of the escape sequence after `\x1b`, e.g. `\x1b[A` for `up`
+fn read_escape<R: Read>(input: &mut R) -> io::Result<Option<Key>> {
+    let Some(byte) = read_byte(input)? else {
+        return Ok(None);
+    };
+    if byte != b'[' && byte != b'O' {
+        return Ok(Some(Key::Alt(byte as char)));
+    }
+
+    let mut params = String::new();
+    let key = loop {
+        match read_byte(input)? {
+            Some(byte @ 0x30..=0x3f) => params.push(byte as char),
+            Some(byte) => break byte,
+            None => return Ok(None),
+        }
+    };
+
+    let key = match (key, params.as_str()) {
+        (b'A', _) => Key::Up,
+        (b'B', _) => Key::Down,
+        (b'C', _) => Key::Right,
+        (b'D', _) => Key::Left,
+        (b'H', _) | (b'~', "1" | "7") => Key::Home,
+        (b'F', _) | (b'~', "4" | "8") => Key::End,
+        (b'~', "3") => Key::Delete,
+        _ => Key::Unknown,
+    };
+    Ok(Some(key))
+}
+
+/// Terminal without echo nor line buffering, restored when dropped
+#[cfg(unix)]
+struct RawMode {
+    original: libc::termios,
+}
+
+#[cfg(unix)]
+impl RawMode {
+    fn enable() -> io::Result<Self> {
+        unsafe {
+            let mut original = std::mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut original) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let mut raw = original;
+            raw.c_iflag &= !(libc::BRKINT | libc::ICRNL | libc::INPCK | libc::ISTRIP | libc::IXON);
+            raw.c_oflag &= !libc::OPOST;
+            raw.c_cflag |= libc::CS8;
+            raw.c_lflag &= !(libc::ECHO | libc::ICANON | libc::IEXTEN | libc::ISIG);
+            raw.c_cc[libc::VMIN] = 1;
+            raw.c_cc[libc::VTIME] = 0;
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSAFLUSH, &raw) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            Ok(Self { original })
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSAFLUSH, &self.original);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Cursor};
+
+    use super::Editor;
+
+    fn edit(editor: &Editor, keys: &str) -> io::Result<Option<String>> {
+        let complete = |word: &str| {
+            ["def", "defined", "extern", "fib", ":funcs"]
+                .iter()
+                .filter(|candidate| candidate.starts_with(word))
+                .map(|candidate| candidate.to_string())
+                .collect()
+        };
+        editor.edit("> ", &mut Cursor::new(keys), &mut Vec::new(), &complete)
+    }
+
+    #[test]
+    fn test_edit() {
+        let mut editor = Editor::new();
+        let line = |keys: &str| edit(&editor, keys).unwrap();
+
+        // cursor movement, deletion and utf-8
+        assert_eq!(line("1 + 3\x1b[D2 + \r"), Some("1 + 2 + 3".into()));
+        assert_eq!(
+            line("abc\x01x\x05y\x1b[3~\x02\x02\x7f\r"),
+            Some("xacy".into())
+        );
+        assert_eq!(line("foo bar baz\x17\x17qux\r"), Some("foo qux".into()));
+        assert_eq!(line("foo bar\x1bb\x0b\x15é\r"), Some("é".into()));
+        assert_eq!(line("1 + 2"), Some("1 + 2".into()));
+        assert_eq!(line("\x04"), None);
+        assert_eq!(line(""), None);
+        let interrupted = edit(&editor, "1 +\x03").unwrap_err();
+        assert_eq!(interrupted.kind(), io::ErrorKind::Interrupted);
+
+        // completion
+        assert_eq!(line("f\t(1)\r"), Some("fib(1)".into()));
+        assert_eq!(line("d\t\t\r"), Some("def".into()));
+        assert_eq!(line(":f\t\r"), Some(":funcs".into()));
+        assert_eq!(line("x\t\r"), Some("x".into()));
+
+        // history
+        for entry in ["def f(x) x", "f(1)", "f(1)", "  ", "extern sin(x)"] {
+            editor.add_history(entry);
+        }
+        assert_eq!(editor.history(), ["def f(x) x", "f(1)", "extern sin(x)"]);
+        let line = |keys: &str| edit(&editor, keys).unwrap();
+        assert_eq!(line("\x1b[A\r"), Some("extern sin(x)".into()));
+        assert_eq!(
+            line("\x1b[A\x1b[A\x1b[A\x1b[A\r"),
+            Some("def f(x) x".into())
+        );
+        assert_eq!(line("new\x10\x10\x0e\x0e\r"), Some("new".into()));
+        assert_eq!(line("\x1b[A\x1b[A\x01\x7f\x1b[3~g\r"), Some("g(1)".into()));
+
+        // reverse search, `enter` runs the entry and other keys edit it
+        assert_eq!(line("\x12f(\r"), Some("f(1)".into()));
+        assert_eq!(line("\x12f(\x12\r"), Some("def f(x) x".into()));
+        assert_eq!(line("\x12sin\x1b[D\x7f\r"), Some("extern sin()".into()));
+        assert_eq!(line("x\x12nope\r\r"), Some("x".into()));
+        assert_eq!(line("x\x12f\x07y\r"), Some("xy".into()));
+    }
+
+    #[test]
+    fn test_history_file() {
+        let path =
+            std::env::temp_dir().join(format!("kaleidoscope-history-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut editor = Editor::with_history_file(&path);
+        assert!(editor.history().is_empty());
+        editor.add_history("def f(x) x\n");
+        editor.add_history("f(2)");
+
+        let editor = Editor::with_history_file(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(editor.history(), ["def f(x) x", "f(2)"]);
+    }
+}
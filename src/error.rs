@@ -1,6 +1,9 @@
 use std::fmt::Display;
 
-use crate::lex::Token;
+use crate::{
+    ast::OpSymbol,
+    lex::{Span, Token},
+};
 
 #[derive(Debug)]
 pub enum Error {
@@ -11,7 +14,31 @@ pub enum Error {
 
 impl Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self)
+        match self {
+            Error::LexError(error) => error.fmt(f),
+            Error::ParserError(error) => error.fmt(f),
+            Error::CompileError(error) => error.fmt(f),
+        }
+    }
+}
+
+impl Error {
+    /// Span of the source which caused the error, if it comes from the source
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            Error::LexError(error) => error.span(),
+            Error::ParserError(error) => Some(error.span()),
+            Error::CompileError(error) => error.span(),
+        }
+    }
+
+    /// Whether the parser failed at the end of `source`, so that more input may complete it,
+    /// e.g. an unclosed `(`, a `def` without body or a trailing operator
+    pub fn is_incomplete(&self, source: &str) -> bool {
+        match self {
+            Error::ParserError(error) => error.span().start >= source.len(),
+            _ => false,
+        }
     }
 }
 
@@ -47,58 +74,132 @@ impl_error_from!(
 #[derive(Debug, thiserror::Error)]
 pub enum LexError {
     #[error("unexpected symbol: {0}")]
-    UnsupportSymbol(char),
+    UnsupportSymbol(char, Span),
     #[error("string unclosed, expected close by: {0}")]
-    UnclosedString(char),
+    UnclosedString(char, Span),
     #[error("parse float occurs error: {0}")]
-    ParseFloatError(std::num::ParseFloatError),
+    ParseFloatError(std::num::ParseFloatError, Span),
     #[error("occurs io error: {0}")]
     IoError(std::io::Error),
 }
 
-impl_error_from!(
-    LexError,
-    LexError::IoError => std::io::Error,
-    LexError::ParseFloatError => std::num::ParseFloatError
-);
+impl LexError {
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            LexError::UnsupportSymbol(_, span)
+            | LexError::UnclosedString(_, span)
+            | LexError::ParseFloatError(_, span) => Some(*span),
+            LexError::IoError(_) => None,
+        }
+    }
+}
+
+impl_error_from!(LexError, LexError::IoError => std::io::Error);
 
 #[derive(Debug, thiserror::Error)]
 pub enum ParserError {
-    #[error("{self:?}")]
-    ExpectedFunctionName,
+    #[error("expected function name in prototype")]
+    ExpectedFunctionName(Span),
     #[error("token '{0:?}' is unsupported operator")]
-    ParseOpSymbolError(Token),
+    ParseOpSymbolError(Token, Span),
     #[error("unexpected token: {0:?}")]
-    UnexpectedToken(Token),
+    UnexpectedToken(Token, Span),
     #[error("{0}")]
-    SyntaxError(String),
+    SyntaxError(String, Span),
 }
 
 impl ParserError {
-    pub fn syn_err<T, S>(err: S) -> crate::Result<T>
+    pub fn syn_err<T, S>(err: S, span: Span) -> crate::Result<T>
     where
         S: AsRef<[u8]>,
     {
-        Err(Self::SyntaxError(String::from_utf8_lossy(err.as_ref()).to_string()).into())
+        Err(Self::SyntaxError(String::from_utf8_lossy(err.as_ref()).to_string(), span).into())
+    }
+
+    pub fn span(&self) -> Span {
+        match self {
+            ParserError::ExpectedFunctionName(span)
+            | ParserError::ParseOpSymbolError(_, span)
+            | ParserError::UnexpectedToken(_, span)
+            | ParserError::SyntaxError(_, span) => *span,
+        }
+    }
+
+    /// Move the error to `span`
+    pub fn with_span(mut self, span: Span) -> Self {
+        match &mut self {
+            ParserError::ExpectedFunctionName(old)
+            | ParserError::ParseOpSymbolError(_, old)
+            | ParserError::UnexpectedToken(_, old)
+            | ParserError::SyntaxError(_, old) => *old = span,
+        }
+        self
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, thiserror::Error)]
 pub enum CompileError {
+    #[error("llvm returned a null pointer")]
     PointerIsNull,
-    UnknowVariableName(String),
-    UnknowFunction(String),
-    IncorrectArguments{expect: usize, get: usize},
+    #[error("cannot find variable `{0}` in this scope")]
+    UnknowVariableName(String, Span),
+    #[error("cannot find function `{0}`")]
+    UnknowFunction(String, Span),
+    /// `declared` is the span of the called function's prototype, if it is known
+    #[error("this function takes {expect} arguments but {get} were supplied")]
+    IncorrectArguments {
+        expect: usize,
+        get: usize,
+        span: Span,
+        declared: Option<Span>,
+    },
+    #[error("llvm returned a null function argument")]
     FunctionArgumentIsNull,
-    FunctionRedifined,
-    CreateJitError(std::io::Error)
+    #[error("function is defined multiple times")]
+    FunctionRedifined(Span),
+    #[error("invalid left-hand side of assignment")]
+    InvalidAssignTarget(Span),
+    /// an [`crate::ast::ExprAst::Error`] left by the parser can't be compiled
+    #[error("expression with syntax errors can't be compiled")]
+    InvalidExpression(Span),
+    /// operator can't be applied to evaluated operands, e.g. `=` or `&&`
+    #[error("operator `{0}` can't be applied here")]
+    UnsupportedBinaryOperator(OpSymbol, Span),
+    #[error("optimization passes failed: {0}")]
+    RunPassesError(String),
+    /// the llvm verifier rejected the code generated for `function`
+    #[error("invalid llvm ir in `{function}`: {message}")]
+    InvalidIr { function: String, message: String },
+    #[error("can't create the jit: {0}")]
+    CreateJitError(std::io::Error),
+    #[error("jit error: {0}")]
+    JitError(std::io::Error),
+    /// the compiler was created without jit, see [`crate::compile::Compiler::with_target`]
+    #[error("the compiler has no jit to run code")]
+    JitUnavailable,
+    #[error("can't write the output: {0}")]
+    WriteOutputError(std::io::Error),
+    /// an executable needs `def main()` or top-level expressions
+    #[error("no `main` function or top-level expression to run")]
+    MissingMain,
+    #[error("linking failed: {0}")]
+    LinkError(std::io::Error),
 }
 
-impl Display for CompileError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self)
+impl CompileError {
+    /// Span of the source which caused the error, errors of llvm or the system have none
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            CompileError::UnknowVariableName(_, span)
+            | CompileError::UnknowFunction(_, span)
+            | CompileError::IncorrectArguments { span, .. }
+            | CompileError::FunctionRedifined(span)
+            | CompileError::InvalidAssignTarget(span)
+            | CompileError::InvalidExpression(span)
+            | CompileError::UnsupportedBinaryOperator(_, span) => Some(*span),
+            _ => None,
+        }
     }
 }
 
-impl_error!(CompileError);
 impl_error_from!(CompileError, CompileError::CreateJitError => std::io::Error);
\ No newline at end of file
@@ -1,88 +1,166 @@
 use llvm_sys::{
-    execution_engine::{
-        LLVMCreateExecutionEngineForModule, LLVMExecutionEngineRef, LLVMLinkInMCJIT,
-    },
+    core::{LLVMSetDataLayout, LLVMSetTarget},
+    execution_engine::{LLVMCreateGDBRegistrationListener, LLVMLinkInMCJIT},
     orc2::{
-        ee::LLVMOrcCreateRTDyldObjectLinkingLayerWithSectionMemoryManager,
         lljit::{
-            LLVMOrcCreateLLJIT, LLVMOrcCreateLLJITBuilder, LLVMOrcLLJITBuilderSetJITTargetMachineBuilder, LLVMOrcLLJITGetExecutionSession,
-            LLVMOrcLLJITGetGlobalPrefix, LLVMOrcLLJITMangleAndIntern, LLVMOrcLLJITRef,
+            LLVMOrcCreateLLJIT, LLVMOrcCreateLLJITBuilder, LLVMOrcDisposeLLJIT,
+            LLVMOrcLLJITAddLLVMIRModuleWithRT, LLVMOrcLLJITBuilderSetJITTargetMachineBuilder,
+            LLVMOrcLLJITBuilderSetObjectLinkingLayerCreator, LLVMOrcLLJITGetDataLayoutStr,
+            LLVMOrcLLJITGetGlobalPrefix, LLVMOrcLLJITGetMainJITDylib,
+            LLVMOrcLLJITGetTripleString, LLVMOrcLLJITLookup, LLVMOrcLLJITMangleAndIntern,
+            LLVMOrcLLJITRef,
+        },
+        ee::{
+            LLVMOrcCreateRTDyldObjectLinkingLayerWithSectionMemoryManager,
+            LLVMOrcRTDyldObjectLinkingLayerRegisterJITEventListener,
         },
-        LLVMOrcCreateDynamicLibrarySearchGeneratorForProcess,
-        LLVMOrcDefinitionGeneratorRef, LLVMOrcExecutionSessionCreateBareJITDylib, LLVMOrcExecutionSessionRef,
-        LLVMOrcJITDylibAddGenerator, LLVMOrcJITDylibRef,
-        LLVMOrcJITTargetMachineBuilderCreateFromTargetMachine,
-        LLVMOrcObjectLayerRef, LLVMOrcSymbolStringPoolEntryRef,
+        LLVMJITEvaluatedSymbol, LLVMJITSymbolFlags, LLVMJITSymbolGenericFlags, LLVMOrcAbsoluteSymbols,
+        LLVMOrcCSymbolMapPair, LLVMOrcJITDylibDefine,
+        LLVMOrcCreateDynamicLibrarySearchGeneratorForProcess, LLVMOrcCreateNewThreadSafeContext,
+        LLVMOrcCreateNewThreadSafeModule, LLVMOrcDefinitionGeneratorRef,
+        LLVMOrcDisposeThreadSafeContext, LLVMOrcExecutionSessionRef, LLVMOrcExecutorAddress,
+        LLVMOrcJITDylibAddGenerator, LLVMOrcJITDylibCreateResourceTracker,
+        LLVMOrcJITDylibGetDefaultResourceTracker, LLVMOrcJITDylibRef,
+        LLVMOrcJITTargetMachineBuilderCreateFromTargetMachine, LLVMOrcObjectLayerRef,
+        LLVMOrcReleaseResourceTracker,
+        LLVMOrcResourceTrackerRef, LLVMOrcResourceTrackerRemove, LLVMOrcThreadSafeContextGetContext,
+        LLVMOrcThreadSafeContextRef,
+    },
+    prelude::{LLVMContextRef, LLVMModuleRef},
+    target::{
+        LLVMDisposeTargetData, LLVMTargetDataRef, LLVM_InitializeNativeAsmPrinter,
+        LLVM_InitializeNativeTarget,
     },
-    prelude::LLVMModuleRef,
-    target::{LLVMTargetDataRef, LLVM_InitializeNativeAsmPrinter, LLVM_InitializeNativeTarget},
     target_machine::{
         LLVMCodeGenOptLevel, LLVMCodeModel, LLVMCreateTargetDataLayout, LLVMCreateTargetMachine,
         LLVMGetDefaultTargetTriple, LLVMGetHostCPUFeatures, LLVMGetHostCPUName,
-        LLVMGetTargetFromTriple, LLVMNormalizeTargetTriple, LLVMRelocMode, LLVMTargetMachineRef,
-        LLVMTargetRef,
+        LLVMDisposeTargetMachine, LLVMNormalizeTargetTriple, LLVMRelocMode,
+        LLVMTargetMachineRef,
     },
 };
 use std::{
     ffi::CString,
-    io::Error as IoError,
+    io::{self, Error as IoError, Write},
     mem,
     ptr::null_mut,
 };
 
-use crate::{get_error_msg, LLVM_SUCCESS};
+use crate::{get_error_msg, target::get_target};
 
 pub struct KaleicoscopeJit {
-    execution_session: LLVMOrcExecutionSessionRef,
-    target_machine: LLVMTargetMachineRef,
     data_layout: LLVMTargetDataRef,
+    /// machine of the optimizations, the jit owns the one it compiles with
+    machine: LLVMTargetMachineRef,
     jit: LLVMOrcLLJITRef,
-    object_layer: LLVMOrcObjectLayerRef,
     main_jd: LLVMOrcJITDylibRef,
-    mangle_and_interner: LLVMOrcSymbolStringPoolEntryRef,
+    thread_safe_context: LLVMOrcThreadSafeContextRef,
+}
+
+/// Tracks the code added to the jit with it, see [`KaleicoscopeJit::add_module`]
+pub struct ResourceTracker(LLVMOrcResourceTrackerRef);
+
+impl ResourceTracker {
+    /// Remove all code added with this tracker from the jit
+    pub fn remove(&self) -> Result<(), IoError> {
+        unsafe {
+            let error = LLVMOrcResourceTrackerRemove(self.0);
+            if error.is_null() {
+                Ok(())
+            } else {
+                Err(IoError::other(get_error_msg(error)))
+            }
+        }
+    }
+}
+
+impl Drop for ResourceTracker {
+    fn drop(&mut self) {
+        unsafe { LLVMOrcReleaseResourceTracker(self.0) }
+    }
 }
 
 impl KaleicoscopeJit {
     pub fn create() -> Result<Self, IoError> {
         unsafe {
             let target_machine = Self::create_tm()?;
-            // let jtmb = LLVMOrcJITTargetMachineBuilderCreateFromTargetMachine(target_machine);
 
             let data_layout = LLVMCreateTargetDataLayout(target_machine);
+            // the jit takes the ownership of `target_machine`
             let jit = Self::create_jit(target_machine)?;
-            let es = LLVMOrcLLJITGetExecutionSession(jit);
-            let object_layer = LLVMOrcCreateRTDyldObjectLinkingLayerWithSectionMemoryManager(es);
-
-            let main_jd_name = CString::new("<main>").unwrap();
-            let main_jd = LLVMOrcExecutionSessionCreateBareJITDylib(es, main_jd_name.as_ptr());
+            let machine = Self::create_tm()?;
 
+            let main_jd = LLVMOrcLLJITGetMainJITDylib(jit);
             LLVMOrcJITDylibAddGenerator(main_jd, Self::create_generator(jit)?);
-
-            let mangle_name = CString::new("mangle").unwrap();
-            let mangle_and_interner = LLVMOrcLLJITMangleAndIntern(jit, mangle_name.as_ptr());
+            Self::define_runtime(jit, main_jd)?;
 
             Ok(Self {
-                execution_session: es,
                 data_layout,
+                machine,
                 jit,
-                target_machine,
-                object_layer,
                 main_jd,
-                mangle_and_interner,
+                thread_safe_context: LLVMOrcCreateNewThreadSafeContext(),
             })
         }
     }
 
-    unsafe fn get_target(triple: *const ::libc::c_char) -> Result<LLVMTargetRef, IoError> {
-        let mut target = mem::MaybeUninit::uninit();
-        let mut error = mem::zeroed();
-        if LLVMGetTargetFromTriple(triple, target.as_mut_ptr(), &mut error) != LLVM_SUCCESS {
-            if !error.is_null() {
-                return Err(IoError::other(CString::from_raw(error).to_string_lossy()));
+    /// Context the modules given to [`KaleicoscopeJit::add_module`] must be created in
+    pub fn context(&self) -> LLVMContextRef {
+        unsafe { LLVMOrcThreadSafeContextGetContext(self.thread_safe_context) }
+    }
+
+    pub(crate) fn target_machine(&self) -> LLVMTargetMachineRef {
+        self.machine
+    }
+
+    /// Set data layout and target triple of `module` to the jit's
+    pub(crate) fn setup_module(&self, module: LLVMModuleRef) {
+        unsafe {
+            LLVMSetDataLayout(module, LLVMOrcLLJITGetDataLayoutStr(self.jit));
+            LLVMSetTarget(module, LLVMOrcLLJITGetTripleString(self.jit));
+        }
+    }
+
+    pub fn create_resource_tracker(&self) -> ResourceTracker {
+        unsafe { ResourceTracker(LLVMOrcJITDylibCreateResourceTracker(self.main_jd)) }
+    }
+
+    /// Add `module` to the main dylib, the jit takes the ownership of `module`.
+    ///
+    /// With `tracker` the code can be removed later by [`ResourceTracker::remove`],
+    /// otherwise it lives as long as the jit.
+    pub(crate) fn add_module(
+        &self,
+        module: LLVMModuleRef,
+        tracker: Option<&ResourceTracker>,
+    ) -> Result<(), IoError> {
+        unsafe {
+            let tsm = LLVMOrcCreateNewThreadSafeModule(module, self.thread_safe_context);
+            let tracker = match tracker {
+                Some(tracker) => tracker.0,
+                None => LLVMOrcJITDylibGetDefaultResourceTracker(self.main_jd),
+            };
+
+            let error = LLVMOrcLLJITAddLLVMIRModuleWithRT(self.jit, tracker, tsm);
+            if error.is_null() {
+                Ok(())
+            } else {
+                Err(IoError::other(get_error_msg(error)))
             }
         }
+    }
 
-        Ok(target.assume_init())
+    /// Address of the symbol `name`, materializing it if needed
+    pub fn lookup(&self, name: &str) -> Result<LLVMOrcExecutorAddress, IoError> {
+        unsafe {
+            let name = CString::new(name).unwrap();
+            let mut address = 0;
+            let error = LLVMOrcLLJITLookup(self.jit, &mut address, name.as_ptr());
+            if error.is_null() {
+                Ok(address)
+            } else {
+                Err(IoError::other(get_error_msg(error)))
+            }
+        }
     }
 
     unsafe fn create_tm() -> Result<LLVMTargetMachineRef, IoError> {
@@ -91,7 +169,7 @@ impl KaleicoscopeJit {
         LLVM_InitializeNativeAsmPrinter();
 
         let triple = LLVMNormalizeTargetTriple(LLVMGetDefaultTargetTriple());
-        let target = Self::get_target(triple)?;
+        let target = get_target(triple)?;
         let cpu = LLVMGetHostCPUName();
         let features = LLVMGetHostCPUFeatures();
 
@@ -117,6 +195,11 @@ impl KaleicoscopeJit {
 
         let jit_builder = LLVMOrcCreateLLJITBuilder();
         LLVMOrcLLJITBuilderSetJITTargetMachineBuilder(jit_builder, jit_target_machine_builder);
+        LLVMOrcLLJITBuilderSetObjectLinkingLayerCreator(
+            jit_builder,
+            Self::create_object_layer,
+            null_mut(),
+        );
 
         let mut jit_ref = mem::MaybeUninit::uninit();
         let err = LLVMOrcCreateLLJIT(jit_ref.as_mut_ptr(), jit_builder);
@@ -127,21 +210,20 @@ impl KaleicoscopeJit {
         Ok(jit_ref.assume_init())
     }
 
-    unsafe fn create_execution_engine(
-        module: LLVMModuleRef,
-    ) -> Result<LLVMExecutionEngineRef, IoError> {
-        let mut ee_ref = mem::MaybeUninit::uninit();
-        let mut error = mem::zeroed();
-
-        if LLVMCreateExecutionEngineForModule(ee_ref.as_mut_ptr(), module, &mut error)
-            != LLVM_SUCCESS
-        {
-            if !error.is_null() {
-                return Err(IoError::other(CString::from_raw(error).to_string_lossy()));
-            }
+    /// Object linking layer which registers the jitted code to gdb, so that it can be debugged
+    extern "C" fn create_object_layer(
+        _ctx: *mut ::libc::c_void,
+        session: LLVMOrcExecutionSessionRef,
+        _triple: *const ::libc::c_char,
+    ) -> LLVMOrcObjectLayerRef {
+        unsafe {
+            let layer = LLVMOrcCreateRTDyldObjectLinkingLayerWithSectionMemoryManager(session);
+            LLVMOrcRTDyldObjectLinkingLayerRegisterJITEventListener(
+                layer,
+                LLVMCreateGDBRegistrationListener(),
+            );
+            layer
         }
-
-        Ok(ee_ref.assume_init())
     }
 
     unsafe fn create_generator(
@@ -160,6 +242,63 @@ impl KaleicoscopeJit {
             Ok(generator.assume_init())
         }
     }
+
+    /// Define the functions of the runtime, see [`printd`] and [`putchard`]
+    unsafe fn define_runtime(
+        jit: LLVMOrcLLJITRef,
+        main_jd: LLVMOrcJITDylibRef,
+    ) -> Result<(), IoError> {
+        let generic_flags = LLVMJITSymbolGenericFlags::LLVMJITSymbolGenericFlagsExported as u8
+            | LLVMJITSymbolGenericFlags::LLVMJITSymbolGenericFlagsCallable as u8;
+        let runtime: [(&str, extern "C" fn(f64) -> f64); 2] =
+            [("printd", printd), ("putchard", putchard)];
+        let mut symbols = runtime.map(|(name, function)| {
+            let name = CString::new(name).unwrap();
+            LLVMOrcCSymbolMapPair {
+                Name: LLVMOrcLLJITMangleAndIntern(jit, name.as_ptr()),
+                Sym: LLVMJITEvaluatedSymbol {
+                    Address: function as usize as LLVMOrcExecutorAddress,
+                    Flags: LLVMJITSymbolFlags {
+                        GenericFlags: generic_flags,
+                        TargetFlags: 0,
+                    },
+                },
+            }
+        });
+
+        let units = LLVMOrcAbsoluteSymbols(symbols.as_mut_ptr(), symbols.len());
+        let error = LLVMOrcJITDylibDefine(main_jd, units);
+        if !error.is_null() {
+            Err(IoError::other(get_error_msg(error)))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Print `x` followed by a newline, like `printd` of `runtime/runtime.c` for executables
+extern "C" fn printd(x: f64) -> f64 {
+    println!("{x:.6}");
+    0.0
+}
+
+/// Print `x` as an ascii char, like `putchard` of `runtime/runtime.c` for executables
+extern "C" fn putchard(x: f64) -> f64 {
+    let mut stdout = io::stdout();
+    let _ = stdout.write_all(&[x as u8]);
+    let _ = stdout.flush();
+    0.0
+}
+
+impl Drop for KaleicoscopeJit {
+    fn drop(&mut self) {
+        unsafe {
+            LLVMOrcDisposeLLJIT(self.jit);
+            LLVMOrcDisposeThreadSafeContext(self.thread_safe_context);
+            LLVMDisposeTargetData(self.data_layout);
+            LLVMDisposeTargetMachine(self.machine);
+        }
+    }
 }
 
 #[cfg(test)]
@@ -174,4 +313,19 @@ mod test {
             Err(e) => panic!("{}", e),
         }
     }
+
+    #[test]
+    fn test_runtime() {
+        use crate::compile::Compiler;
+        use crate::parser::Parser;
+
+        // defined by the jit, even if the process doesn't export them
+        let mut compiler = Compiler::new().unwrap();
+        compiler.compile_source("extern printd(x); extern putchard(c)").unwrap();
+        let expr = Parser::new("printd(1) + putchard(10) + 1")
+            .unwrap()
+            .parse_toplevel()
+            .unwrap();
+        assert_eq!(compiler.eval_toplevel(&expr).unwrap(), 1.0);
+    }
 }
@@ -10,6 +10,24 @@ pub enum Token {
     Def,
     /// keyword `extern`
     Extern,
+    /// keyword `export`
+    Export,
+    /// keyword `if`
+    If,
+    /// keyword `then`
+    Then,
+    /// keyword `else`
+    Else,
+    /// keyword `for`
+    For,
+    /// keyword `in`
+    In,
+    /// keyword `binary`
+    Binary,
+    /// keyword `unary`
+    Unary,
+    /// keyword `var`
+    Var,
     /// char `(`
     LeftBracket,
     /// char `)`
@@ -46,8 +64,16 @@ pub enum Token {
     LessEq,
     /// symbol `>=`
     GreaEq,
+    /// symbol `&&`
+    And,
+    /// symbol `||`
+    Or,
     /// char `,`
     Comma,
+    /// char `;`, separates statements
+    Semicolon,
+    /// any other ascii punctuation, can be used as user-defined operator
+    Operator(char),
     /// function or variable identifier
     Identifier(String),
     Number(f64),
@@ -55,17 +81,28 @@ pub enum Token {
     EOF,
 }
 
-impl Token {
-    pub fn precedence(&self) -> i8 {
-        Token::get_tok_prec(self)
+/// Location of a piece of source: the bytes `start..end`, whose first one is
+/// at `line` and `column` (both start at 1)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: u32,
+    pub column: u32,
+}
+
+impl std::fmt::Display for Span {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
     }
+}
 
-    pub fn get_tok_prec(token: &Self) -> i8 {
-        match token {
-            Token::LeftAngle | Token::RightAngle => 1,
-            Token::Add | Token::Minus => 5,
-            Token::Mul | Token::Div => 10,
-            _ => -1,
+impl Span {
+    /// Span from the start of `self` to the end of `other`
+    pub fn to(self, other: Span) -> Span {
+        Span {
+            end: other.end,
+            ..self
         }
     }
 }
@@ -75,7 +112,13 @@ where
     S: AsRef<[u8]>,
 {
     input: Cursor<S>,
-    ahead: Option<Token>,
+    ahead: Option<(Token, Span)>,
+    /// offset of the first byte of each line
+    line_starts: Vec<usize>,
+    /// offset of the token being read
+    start: usize,
+    /// span of the last token returned by [`Lexer::next`]
+    span: Span,
 }
 
 impl<S> Lexer<S>
@@ -83,32 +126,71 @@ where
     S: AsRef<[u8]>,
 {
     pub fn new(input: S) -> Self {
+        let line_starts = std::iter::once(0)
+            .chain(
+                input
+                    .as_ref()
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, byte)| **byte == b'\n')
+                    .map(|(offset, _)| offset + 1),
+            )
+            .collect();
+
         Self {
             input: Cursor::new(input),
             ahead: None,
+            line_starts,
+            start: 0,
+            span: Span::default(),
         }
     }
 
     pub fn next(&mut self) -> Result<Token> {
-        match self.ahead.take() {
-            Some(t) => Ok(t),
-            None => self.do_next(),
-        }
+        let (token, span) = match self.ahead.take() {
+            Some(ahead) => ahead,
+            None => self.read_token()?,
+        };
+        self.span = span;
+
+        Ok(token)
     }
 
     pub fn peek(&mut self) -> Result<&Token> {
-        if self.ahead.is_none() || self.ahead == Some(Token::EOF) {
-            self.ahead = Some(self.do_next()?);
+        if self.ahead.is_none() {
+            self.ahead = Some(self.read_token()?);
         }
 
         match &self.ahead {
-            Some(ahead) => Ok(ahead),
+            Some((ahead, _)) => Ok(ahead),
             None => Ok(&Token::EOF),
         }
     }
 
+    /// Span of the last token returned by [`Lexer::next`]
+    pub fn span(&self) -> Span {
+        self.span
+    }
+
+    fn read_token(&mut self) -> Result<(Token, Span)> {
+        let token = self.do_next()?;
+        Ok((token, self.current_span()))
+    }
+
+    /// Span from the start of the token being read to the current position
+    fn current_span(&self) -> Span {
+        let line = self.line_starts.partition_point(|&start| start <= self.start);
+        Span {
+            start: self.start,
+            end: self.input.position() as usize,
+            line: line as u32,
+            column: (self.start - self.line_starts[line - 1] + 1) as u32,
+        }
+    }
+
     fn do_next(&mut self) -> Result<Token> {
         self.skip_whitespace()?;
+        self.start = self.input.position() as usize;
 
         if let Some(ch) = self.next_char()? {
             match ch {
@@ -125,6 +207,9 @@ where
                 '}' => Ok(Token::RightCurly),
                 ',' => Ok(Token::Comma),
                 '=' => self.read_ahead('=', Token::Equal, Token::Assign),
+                '!' => self.read_ahead('=', Token::NotEq, Token::Exclamation),
+                '&' => self.read_ahead('&', Token::And, Token::Operator('&')),
+                '|' => self.read_ahead('|', Token::Or, Token::Operator('|')),
                 '>' => self.read_aheadf('=', |_| Ok(Token::GreaEq), |_| Ok(Token::RightAngle)),
                 '<' => self.read_aheadf('=', |_| Ok(Token::LessEq), |_| Ok(Token::LeftAngle)),
                 '#' => {
@@ -133,8 +218,9 @@ where
                 }
                 '.' | '0'..='9' => self.read_number(ch),
                 ch if ch.is_alphanumeric() || ch == '_' => self.read_identifier(ch),
-                ';' => Ok(Token::EOF),
-                _ => Err(LexError::UnsupportSymbol(ch).into())
+                ';' => Ok(Token::Semicolon),
+                ch if ch.is_ascii_punctuation() => Ok(Token::Operator(ch)),
+                _ => Err(LexError::UnsupportSymbol(ch, self.current_span()).into())
             }
         } else {
             Ok(Token::EOF)
@@ -156,6 +242,15 @@ where
         match identifier.as_str() {
             "def" => Ok(Token::Def),
             "extern" => Ok(Token::Extern),
+            "export" => Ok(Token::Export),
+            "if" => Ok(Token::If),
+            "then" => Ok(Token::Then),
+            "else" => Ok(Token::Else),
+            "for" => Ok(Token::For),
+            "in" => Ok(Token::In),
+            "binary" => Ok(Token::Binary),
+            "unary" => Ok(Token::Unary),
+            "var" => Ok(Token::Var),
             _ => Ok(Token::Identifier(identifier)),
         }
     }
@@ -166,7 +261,7 @@ where
         while let Some(ch) = self.next_char()? {
             match ch {
                 ch if ch == quote => break,
-                '\n' => return Err(LexError::UnclosedString(quote).into()),
+                '\n' => return Err(LexError::UnclosedString(quote, self.current_span()).into()),
                 _ => str.push(ch),
             }
         }
@@ -215,7 +310,7 @@ where
                 num_str
                     .parse::<f64>()
                     .map(Token::Number)
-                    .map_err(|e| Into::<LexError>::into(e).into())
+                    .map_err(|e| LexError::ParseFloatError(e, self.current_span()).into())
             }
         }
     }
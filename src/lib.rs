@@ -5,10 +5,15 @@ use llvm_sys::{error::{LLVMErrorRef, LLVMErrorSuccess, LLVMGetErrorMessage}, pre
 
 pub mod lex;
 pub mod error;
+pub mod diagnostic;
 pub mod ast;
 pub mod parser;
 pub mod compile;
+pub mod debug;
 pub mod jit;
+pub mod link;
+pub mod editor;
+pub mod repl;
 pub mod analysis;
 pub mod target;
 
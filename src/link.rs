@@ -0,0 +1,322 @@
+use std::{
+    env,
+    ffi::OsString,
+    fs::{self, DirBuilder},
+    io::{Error as IoError, ErrorKind},
+    path::{Path, PathBuf},
+    process::{self, Command},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{
+    ast::PrototypeAst,
+    compile::Compiler,
+    error::CompileError,
+    target::{OutputKind, RelocMode, Target},
+};
+
+/// Functions available to every executable and shared library, e.g. `printd` and `putchard`
+pub const RUNTIME: &str = include_str!("../runtime/runtime.c");
+
+/// The system linker driver, `$CC` or `cc`. It also compiles the [`RUNTIME`].
+pub fn linker() -> OsString {
+    env::var_os("CC").unwrap_or_else(|| "cc".into())
+}
+
+/// What [`link`] produces
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkKind {
+    Executable,
+    /// needs objects compiled with [`RelocMode::Pic`]
+    SharedLibrary,
+}
+
+/// Link `objects` and the [`RUNTIME`] into `output`
+pub fn link<P: AsRef<Path>>(objects: &[PathBuf], output: P, kind: LinkKind) -> Result<(), IoError> {
+    let dir = TempDir::new()?;
+    let runtime = dir.path().join("runtime.c");
+    fs::write(&runtime, RUNTIME)?;
+
+    let mut command = Command::new(linker());
+    if kind == LinkKind::SharedLibrary {
+        command.arg("-shared").arg("-fPIC");
+    }
+    let linked = command
+        .arg("-o")
+        .arg(output.as_ref())
+        .args(objects)
+        .arg(&runtime)
+        .arg("-lm")
+        .output()?;
+
+    if linked.status.success() {
+        Ok(())
+    } else {
+        Err(IoError::other(format!(
+            "linker failed with {}\n{}",
+            linked.status,
+            String::from_utf8_lossy(&linked.stderr)
+        )))
+    }
+}
+
+/// Compile the kaleidoscope `source` for `target` and link it into the executable `output`,
+/// see [`Compiler::create_main`] for its entry point.
+///
+/// The system linker builds for the host, so `target` should be the host as well.
+pub fn build_executable<P: AsRef<Path>>(
+    source: &str,
+    target: &Target,
+    output: P,
+) -> crate::Result<()> {
+    let output = output.as_ref();
+    let mut compiler = Compiler::with_target(target, &module_name(output))?;
+    compiler.compile_source(source)?;
+    compiler.create_main()?;
+
+    link_module(&compiler, target, output, LinkKind::Executable)
+}
+
+/// Compile the kaleidoscope `source` for `target` into the shared library `output`,
+/// with a C header declaring its `export def` functions next to it (`output` with
+/// the extension `.h`). Other functions are hidden, top-level expressions are never run.
+///
+/// `target` must be created with [`RelocMode::Pic`].
+pub fn build_shared_library<P: AsRef<Path>>(
+    source: &str,
+    target: &Target,
+    output: P,
+) -> crate::Result<()> {
+    if target.reloc_mode() != RelocMode::Pic {
+        return Err(CompileError::LinkError(IoError::other(
+            "shared libraries need position independent code, see `RelocMode::Pic`",
+        ))
+        .into());
+    }
+
+    let output = output.as_ref();
+    let name = module_name(output);
+    let mut compiler = Compiler::with_target(target, &name)?;
+    compiler.compile_source(source)?;
+    compiler.hide_unexported();
+
+    link_module(&compiler, target, output, LinkKind::SharedLibrary)?;
+    fs::write(
+        output.with_extension("h"),
+        c_header(&name, &compiler.exports()),
+    )
+    .map_err(|e| CompileError::WriteOutputError(e).into())
+}
+
+/// C declarations of `exports` as `double name(double, ...)`, `name` is used for the include guard
+pub fn c_header(name: &str, exports: &[&PrototypeAst]) -> String {
+    let guard = name
+        .chars()
+        .map(|ch| {
+            if ch.is_ascii_alphanumeric() {
+                ch.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+
+    let mut header = format!(
+        "/* generated by kaleidoscope, do not edit */\n\
+         #ifndef {guard}_H\n\
+         #define {guard}_H\n\n\
+         #ifdef __cplusplus\n\
+         extern \"C\" {{\n\
+         #endif\n\n"
+    );
+    for proto in exports {
+        let args = if proto.args.is_empty() {
+            "void".to_string()
+        } else {
+            vec!["double"; proto.args.len()].join(", ")
+        };
+        header.push_str(&format!("double {}({args});\n", proto.name));
+    }
+    header.push_str(
+        "\n#ifdef __cplusplus\n\
+         }\n\
+         #endif\n\n\
+         #endif\n",
+    );
+
+    header
+}
+
+/// Name of the module compiled into `output`
+fn module_name(output: &Path) -> String {
+    output
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "main".to_string())
+}
+
+/// Write the module of `compiler` to a temporary object and link it into `output`,
+/// see [`build_executable`] and [`build_shared_library`] for the preparation of the module.
+pub fn link_module(
+    compiler: &Compiler,
+    target: &Target,
+    output: &Path,
+    kind: LinkKind,
+) -> crate::Result<()> {
+    let dir = TempDir::new().map_err(CompileError::LinkError)?;
+    let object = dir
+        .path()
+        .join(format!("module.{}", OutputKind::Object.extension()));
+    compiler.write_to_file(target, &object, OutputKind::Object)?;
+
+    link(std::slice::from_ref(&object), output, kind).map_err(|e| CompileError::LinkError(e).into())
+}
+
+/// A new directory in the temporary directory for the intermediate files of a build,
+/// removed with them when dropped.
+///
+/// Only its owner can access it, and it never is an existing file or link.
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new() -> Result<Self, IoError> {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+        let mut builder = DirBuilder::new();
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+        loop {
+            let count = COUNT.fetch_add(1, Ordering::Relaxed);
+            let path = env::temp_dir().join(format!("kaleidoscope-{}-{count}", process::id()));
+            match builder.create(&path) {
+                Ok(()) => return Ok(Self(path)),
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::process::Command;
+
+    use super::{build_executable, build_shared_library, TempDir};
+    use crate::target::{RelocMode, Target, TargetOptions};
+
+    #[test]
+    fn test_build_executable() {
+        let target = Target::host().unwrap();
+        let dir = std::env::temp_dir().join(format!("kaleidoscope-link-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // top-level expressions run before `main`, whose value is the exit code
+        let source = "
+            extern printd(x);
+            extern putchard(c);
+            def fib(n) if n < 2 then n else fib(n - 1) + fib(n - 2);
+            printd(fib(10));
+            def main() putchard(104) + putchard(105) + putchard(10) + 3;
+        ";
+        let program = dir.join("fib");
+        build_executable(source, &target, &program).unwrap();
+        let output = Command::new(&program).output().unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "55.000000\nhi\n");
+        assert_eq!(output.status.code(), Some(3));
+
+        // without `main` the top-level expressions are wrapped
+        let program = dir.join("toplevel");
+        build_executable("extern printd(x); printd(1 + 2)", &target, &program).unwrap();
+        let output = Command::new(&program).output().unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "3.000000\n");
+        assert_eq!(output.status.code(), Some(0));
+
+        // a declared `main` is left out, unless it is called
+        let program = dir.join("extern_main");
+        build_executable(
+            "extern main(); extern printd(x); printd(4)",
+            &target,
+            &program,
+        )
+        .unwrap();
+        let output = Command::new(&program).output().unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "4.000000\n");
+        assert!(build_executable("extern main(); main()", &target, dir.join("called")).is_err());
+
+        assert!(build_executable("def f(x) x", &target, dir.join("empty")).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_build_shared_library() {
+        let target = Target::create(&TargetOptions::default().reloc_mode(RelocMode::Pic)).unwrap();
+        let dir = std::env::temp_dir().join(format!("kaleidoscope-shared-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let source = "
+            def helper(x) x + 1;
+            export def area(w h) helper(w) * h;
+            export def two() 2;
+        ";
+        let library = dir.join("libkernels.so");
+        build_shared_library(source, &target, &library).unwrap();
+
+        let header = std::fs::read_to_string(dir.join("libkernels.h")).unwrap();
+        assert!(header.contains("#ifndef LIBKERNELS_H"));
+        assert!(header.contains("double area(double, double);\ndouble two(void);\n"));
+        assert!(!header.contains("helper"));
+        let checked = Command::new(super::linker())
+            .args(["-fsyntax-only", "-x", "c"])
+            .arg(dir.join("libkernels.h"))
+            .status()
+            .unwrap();
+        assert!(checked.success());
+
+        unsafe {
+            let path = std::ffi::CString::new(library.to_str().unwrap()).unwrap();
+            let handle = libc::dlopen(path.as_ptr(), libc::RTLD_NOW);
+            assert!(!handle.is_null());
+
+            let area = libc::dlsym(handle, c"area".as_ptr());
+            assert!(!area.is_null());
+            let area: extern "C" fn(f64, f64) -> f64 = std::mem::transmute(area);
+            assert_eq!(area(2.0, 4.0), 12.0);
+            assert!(libc::dlsym(handle, c"helper".as_ptr()).is_null());
+
+            libc::dlclose(handle);
+        }
+
+        // without position independent code
+        let target = Target::host().unwrap();
+        assert!(build_shared_library(source, &target, dir.join("libstatic.so")).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_temp_dir() {
+        let dir = TempDir::new().unwrap();
+        let other = TempDir::new().unwrap();
+        assert_ne!(dir.path(), other.path());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(dir.path()).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o700);
+        }
+
+        let path = dir.path().to_path_buf();
+        std::fs::write(path.join("runtime.c"), "").unwrap();
+        drop(dir);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,502 @@
+use std::{
+    env, fs,
+    io::{self, IsTerminal, Write},
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+use kaleidoscope_rs::{
+    ast::Item,
+    compile::Compiler,
+    diagnostic::{color_enabled, Diagnostic},
+    editor::Editor,
+    error::{CompileError, Error},
+    link::{self, LinkKind},
+    parser::Parser,
+    repl::Repl,
+    target::{OptLevel, OutputKind, RelocMode, Target, TargetOptions},
+};
+
+const USAGE: &str = "\
+usage: kaleidoscope [command] [options] [file.ks]
+
+commands:
+    repl             interactive session with the jit, the default
+    run <file>       compile the file with the jit and print the value of its top-level expressions
+    build <file>     compile the file into an executable, a shared library or an object file
+    emit <file>      write the ast, llvm ir, bitcode, assembly or object of the file
+    check <file>     parse and analyze the file without writing anything
+
+options:
+    -o <path>                   output of `build` and `emit`, derived from the input by default
+    -O0, -O1, -O2, -O3          optimization level, `-O2` by default
+    --passes <pipeline>         llvm pass pipeline run instead, e.g. `function(mem2reg,gvn)`
+    --target <triple>           target triple of `build`, `emit` and `check`, the host by default
+    --cpu <name>                target cpu, the host's or `generic` when cross compiling
+    --emit <kind>               `ast`, `ir`, `bc`, `asm` or `obj`, guessed from `-o` by default
+    -c                          `build` an object file instead of an executable
+    --shared                    `build` a shared library and a C header of its `export def`
+    -g                          emit debug information
+    -h, --help                  print this help
+";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Command {
+    Repl,
+    Run,
+    Build,
+    Emit,
+    Check,
+}
+
+/// What `emit` writes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Emit {
+    Ast,
+    Output(OutputKind),
+}
+
+impl Emit {
+    fn parse(kind: &str) -> Result<Self, String> {
+        match kind {
+            "ast" => Ok(Emit::Ast),
+            "ir" | "ll" => Ok(Emit::Output(OutputKind::LlvmIr)),
+            "bc" => Ok(Emit::Output(OutputKind::Bitcode)),
+            "asm" | "s" => Ok(Emit::Output(OutputKind::Assembly)),
+            "obj" | "o" => Ok(Emit::Output(OutputKind::Object)),
+            _ => Err(format!("unknown kind `{kind}` for `--emit`")),
+        }
+    }
+}
+
+struct Options {
+    command: Command,
+    input: Option<String>,
+    output: Option<PathBuf>,
+    emit: Option<Emit>,
+    target: TargetOptions,
+    debug: bool,
+    /// `--passes`, replaces the pipeline of the optimization level
+    passes: Option<String>,
+    /// `build -c`
+    object: bool,
+    /// `build --shared`
+    shared: bool,
+}
+
+impl Options {
+    fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Self, String> {
+        let mut args = args.into_iter().peekable();
+        let command = match args.peek().map(String::as_str) {
+            Some("repl") => Command::Repl,
+            Some("run") => Command::Run,
+            Some("build") => Command::Build,
+            Some("emit") => Command::Emit,
+            Some("check") => Command::Check,
+            Some(arg) if !arg.starts_with('-') => return Err(format!("unknown command `{arg}`")),
+            _ => Command::Repl,
+        };
+        if args.peek().is_some_and(|arg| !arg.starts_with('-')) {
+            args.next();
+        }
+
+        let mut options = Options {
+            command,
+            input: None,
+            output: None,
+            emit: None,
+            target: TargetOptions::default(),
+            debug: false,
+            passes: None,
+            object: false,
+            shared: false,
+        };
+        while let Some(arg) = args.next() {
+            // `--name value` or `--name=value`
+            let (name, inline) = match arg.split_once('=') {
+                Some((name, value)) if name.starts_with("--") => (name, Some(value.to_string())),
+                _ => (arg.as_str(), None),
+            };
+            let mut value = || {
+                inline
+                    .clone()
+                    .or_else(|| args.next())
+                    .ok_or_else(|| format!("`{name}` needs a value"))
+            };
+
+            match name {
+                "-o" => options.output = Some(value()?.into()),
+                "-O0" => options.target.opt_level = OptLevel::None,
+                "-O1" => options.target.opt_level = OptLevel::Less,
+                "-O2" => options.target.opt_level = OptLevel::Default,
+                "-O3" => options.target.opt_level = OptLevel::Aggressive,
+                "--passes" => options.passes = Some(value()?),
+                "--target" => options.target.triple = Some(value()?),
+                "--cpu" => options.target.cpu = Some(value()?),
+                "--emit" => options.emit = Some(Emit::parse(&value()?)?),
+                "-c" => options.object = true,
+                "--shared" => options.shared = true,
+                "-g" => options.debug = true,
+                _ if name.starts_with('-') => return Err(format!("unknown option `{name}`")),
+                _ if options.input.is_some() => return Err(format!("unexpected argument `{arg}`")),
+                _ => options.input = Some(arg),
+            }
+        }
+
+        if options.command != Command::Repl && options.input.is_none() {
+            return Err("missing the input file".to_string());
+        }
+        if options.shared {
+            options.target.reloc_mode = RelocMode::Pic;
+        }
+        Ok(options)
+    }
+
+    /// Optimizations of `compiler`, `--passes` or the pipeline of the optimization level
+    fn optimize(&self, compiler: &mut Compiler) -> Result<(), Error> {
+        match &self.passes {
+            Some(passes) => compiler.set_passes(passes),
+            None => {
+                compiler.set_opt_level(self.target.opt_level);
+                Ok(())
+            }
+        }
+    }
+
+    /// `-o`, otherwise the input with `extension`
+    fn output(&self, extension: &str) -> PathBuf {
+        match &self.output {
+            Some(output) => output.clone(),
+            None => Path::new(self.input.as_deref().unwrap_or("out")).with_extension(extension),
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    if args.iter().any(|arg| arg == "-h" || arg == "--help")
+        || args.first().is_some_and(|arg| arg == "help")
+    {
+        print!("{USAGE}");
+        return ExitCode::SUCCESS;
+    }
+
+    let options = match Options::parse(args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("error: {e}\n");
+            eprint!("{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let succeeded = match options.command {
+        Command::Repl => repl(&options),
+        Command::Run => run(&options),
+        Command::Build => build(&options),
+        Command::Emit => emit(&options),
+        Command::Check => check(&options),
+    };
+    if succeeded {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+/// A source file, which the diagnostics are rendered against
+struct Source {
+    path: String,
+    text: String,
+}
+
+impl Source {
+    fn read(path: &str) -> Option<Self> {
+        match fs::read_to_string(path) {
+            Ok(text) => Some(Self {
+                path: path.to_string(),
+                text,
+            }),
+            Err(e) => {
+                eprintln!("error: can't read `{path}`: {e}");
+                None
+            }
+        }
+    }
+
+    fn report(&self, error: &Error) {
+        eprint!(
+            "{}",
+            Diagnostic::from(error).render(&self.path, &self.text, color_enabled(&io::stderr()))
+        );
+    }
+
+    /// Parse the whole source, every syntax error is reported
+    fn parse(&self) -> Option<Vec<Item>> {
+        let (items, errors) = match Parser::new(self.text.as_str()) {
+            Ok(mut parser) => parser.parse_all(),
+            Err(e) => (Vec::new(), vec![e]),
+        };
+        errors.iter().for_each(|error| self.report(error));
+
+        errors.is_empty().then_some(items)
+    }
+
+    /// Name of the module compiled from the source
+    fn module_name(&self) -> String {
+        Path::new(&self.path)
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "main".to_string())
+    }
+}
+
+/// Compile `items` ahead of time for the target of `options`
+fn compile(options: &Options, source: &Source, items: &[Item]) -> Option<(Compiler, Target)> {
+    let target = match Target::create(&options.target) {
+        Ok(target) => target,
+        Err(e) => {
+            eprintln!("error: {e}");
+            return None;
+        }
+    };
+
+    let mut compiler = match Compiler::with_target(&target, &source.module_name()) {
+        Ok(compiler) => compiler,
+        Err(e) => {
+            source.report(&e);
+            return None;
+        }
+    };
+    if let Err(e) = options.optimize(&mut compiler) {
+        source.report(&e);
+        return None;
+    }
+    if options.debug {
+        compiler.enable_debug_info(&source.path);
+    }
+    for item in items {
+        if let Err(e) = compiler.compile_item(item) {
+            source.report(&e);
+            return None;
+        }
+    }
+
+    Some((compiler, target))
+}
+
+/// Interactive session, see [`Repl`]
+fn repl(options: &Options) -> bool {
+    let mut repl = match Repl::new() {
+        Ok(repl) => repl,
+        Err(e) => {
+            eprintln!("error: {e}");
+            return false;
+        }
+    };
+    if let Err(e) = options.optimize(repl.compiler_mut()) {
+        eprintln!("error: {e}");
+        return false;
+    }
+    // the repl prints its diagnostics to stdout
+    repl.color = color_enabled(&io::stdout());
+    // piped input is not remembered
+    let mut editor = match Editor::default_history_file() {
+        Some(path) if io::stdin().is_terminal() => Editor::with_history_file(path),
+        _ => Editor::new(),
+    };
+    let mut stdout = io::stdout();
+
+    loop {
+        let complete = |word: &str| repl.complete(word);
+        let fed = match editor.read_line(repl.prompt(), &complete) {
+            Ok(None) => return repl.finish(&mut stdout).is_ok(),
+            Ok(Some(line)) => {
+                editor.add_history(&line);
+                repl.feed(&line, &mut stdout)
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {
+                repl.cancel();
+                Ok(())
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = fed {
+            eprintln!("error: {e}");
+            return false;
+        }
+    }
+}
+
+/// Compile every statement of the input in order with the jit, printing the value of each
+/// top-level expression. Stop at the first error, with every syntax error reported.
+fn run(options: &Options) -> bool {
+    let Some(source) = Source::read(options.input.as_deref().unwrap()) else {
+        return false;
+    };
+    let Some(items) = source.parse() else {
+        return false;
+    };
+
+    let mut compiler = match Compiler::new() {
+        Ok(compiler) => compiler,
+        Err(e) => {
+            source.report(&e);
+            return false;
+        }
+    };
+    if let Err(e) = options.optimize(&mut compiler) {
+        source.report(&e);
+        return false;
+    }
+    if options.debug {
+        compiler.enable_debug_info(&source.path);
+    }
+    for item in &items {
+        let result = match item {
+            Item::Expression(expr) => compiler
+                .eval_toplevel(expr)
+                .map(|value| println!("{value}")),
+            _ => compiler.compile_item(item),
+        };
+        if let Err(e) = result {
+            source.report(&e);
+            return false;
+        }
+    }
+
+    true
+}
+
+/// Build an executable, or an object file with `-c`, or a shared library with `--shared`
+fn build(options: &Options) -> bool {
+    let Some(source) = Source::read(options.input.as_deref().unwrap()) else {
+        return false;
+    };
+    let Some(items) = source.parse() else {
+        return false;
+    };
+    let Some((mut compiler, target)) = compile(options, &source, &items) else {
+        return false;
+    };
+
+    let result = if options.object {
+        let output = options.output(OutputKind::Object.extension());
+        compiler.write_to_file(&target, output, OutputKind::Object)
+    } else if options.shared {
+        compiler.hide_unexported();
+        let output = match &options.output {
+            Some(output) => output.clone(),
+            None => PathBuf::from(format!("lib{}.so", source.module_name())),
+        };
+        link::link_module(&compiler, &target, &output, LinkKind::SharedLibrary).and_then(|_| {
+            let header = link::c_header(&source.module_name(), &compiler.exports());
+            fs::write(output.with_extension("h"), header)
+                .map_err(|e| CompileError::WriteOutputError(e).into())
+        })
+    } else {
+        compiler.create_main().and_then(|_| {
+            let output = options.output(env::consts::EXE_EXTENSION);
+            link::link_module(&compiler, &target, &output, LinkKind::Executable)
+        })
+    };
+
+    match result {
+        Ok(()) => true,
+        Err(e) => {
+            source.report(&e);
+            false
+        }
+    }
+}
+
+/// Write the ast, or the module as guessed from `-o` (llvm ir by default)
+fn emit(options: &Options) -> bool {
+    let Some(source) = Source::read(options.input.as_deref().unwrap()) else {
+        return false;
+    };
+    let emit = options.emit.unwrap_or_else(|| {
+        match options.output.as_ref().and_then(OutputKind::from_path) {
+            Some(kind) => Emit::Output(kind),
+            None => Emit::Output(OutputKind::LlvmIr),
+        }
+    });
+    let Some(items) = source.parse() else {
+        return false;
+    };
+
+    let kind = match emit {
+        Emit::Ast => {
+            let ast = format!("{items:#?}\n");
+            let written = match &options.output {
+                Some(output) => fs::write(output, ast),
+                None => io::stdout().write_all(ast.as_bytes()),
+            };
+            return match written {
+                Ok(()) => true,
+                Err(e) => {
+                    eprintln!("error: can't write the ast: {e}");
+                    false
+                }
+            };
+        }
+        Emit::Output(kind) => kind,
+    };
+
+    let Some((compiler, target)) = compile(options, &source, &items) else {
+        return false;
+    };
+    match compiler.write_to_file(&target, options.output(kind.extension()), kind) {
+        Ok(()) => true,
+        Err(e) => {
+            source.report(&e);
+            false
+        }
+    }
+}
+
+/// Parse and compile the input without writing anything, every syntax error is reported
+fn check(options: &Options) -> bool {
+    let Some(source) = Source::read(options.input.as_deref().unwrap()) else {
+        return false;
+    };
+    let Some(items) = source.parse() else {
+        return false;
+    };
+
+    compile(options, &source, &items).is_some()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Command, Emit, Options};
+    use kaleidoscope_rs::target::{OptLevel, OutputKind, RelocMode};
+
+    #[test]
+    fn test_options() {
+        let parse = |args: &str| Options::parse(args.split_whitespace().map(String::from));
+
+        let options = parse("").unwrap();
+        assert_eq!(options.command, Command::Repl);
+
+        let options = parse("emit fib.ks --emit=bc -O3 --target aarch64-linux-gnu").unwrap();
+        assert_eq!(options.command, Command::Emit);
+        assert_eq!(options.input.as_deref(), Some("fib.ks"));
+        assert_eq!(options.emit, Some(Emit::Output(OutputKind::Bitcode)));
+        assert_eq!(options.target.opt_level, OptLevel::Aggressive);
+        assert_eq!(options.target.triple.as_deref(), Some("aarch64-linux-gnu"));
+        assert_eq!(options.output("bc").to_str(), Some("fib.bc"));
+
+        let options = parse("build --shared -g --cpu generic -o out.so kernels.ks").unwrap();
+        assert!(options.shared && options.debug);
+        assert_eq!(options.target.reloc_mode, RelocMode::Pic);
+        assert_eq!(options.target.cpu.as_deref(), Some("generic"));
+        assert_eq!(options.output("so").to_str(), Some("out.so"));
+
+        assert!(parse("run").is_err());
+        assert!(parse("compile fib.ks").is_err());
+        assert!(parse("run fib.ks --emit").is_err());
+        assert!(parse("run fib.ks --fast").is_err());
+        assert!(parse("run a.ks b.ks").is_err());
+    }
+}
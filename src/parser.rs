@@ -1,18 +1,44 @@
-use std::mem;
+use std::{collections::HashMap, mem};
 
 use crate::{
     ast::*,
-    error::ParserError,
-    lex::{Lexer, Token},
+    error::{Error, ParserError},
+    lex::{Lexer, Span, Token},
     Result,
 };
 
+/// Precedence of a user-defined binary operator declared without one
+const DEFAULT_BINARY_PRECEDENCE: i8 = 30;
+
+/// Precedence of the built-in binary operators
+pub fn default_precedence() -> HashMap<OpSymbol, i8> {
+    HashMap::from([
+        (OpSymbol::Assign, 2),
+        (OpSymbol::Or, 6),
+        (OpSymbol::And, 7),
+        (OpSymbol::Equal, 9),
+        (OpSymbol::NotEq, 9),
+        (OpSymbol::Less, 10),
+        (OpSymbol::Greater, 10),
+        (OpSymbol::LessEq, 10),
+        (OpSymbol::GreaterEq, 10),
+        (OpSymbol::Add, 20),
+        (OpSymbol::Sub, 20),
+        (OpSymbol::Mul, 40),
+        (OpSymbol::Div, 40),
+    ])
+}
+
 pub struct Parser<S>
 where
     S: AsRef<[u8]>,
 {
     inner_lex: Lexer<S>,
     peek: Token,
+    peek_span: Span,
+    /// span of the last token popped
+    prev_span: Span,
+    precedence: HashMap<OpSymbol, i8>,
 }
 
 impl<S> Parser<S>
@@ -20,11 +46,20 @@ where
     S: AsRef<[u8]>,
 {
     pub fn new(input: S) -> Result<Self> {
+        Self::with_precedence(input, default_precedence())
+    }
+
+    /// Create parser with a precedence table, e.g. one kept from an earlier parser
+    /// so that user-defined operators stay usable
+    pub fn with_precedence(input: S, precedence: HashMap<OpSymbol, i8>) -> Result<Self> {
         let mut lex = Lexer::new(input);
         let peek = lex.next()?;
         Ok(Self {
+            peek_span: lex.span(),
+            prev_span: Span::default(),
             inner_lex: lex,
             peek,
+            precedence,
         })
     }
 
@@ -32,45 +67,156 @@ where
         &self.peek
     }
 
+    /// Span of [`Parser::peek`]
+    pub fn span(&self) -> Span {
+        self.peek_span
+    }
+
+    /// Whether the whole input has been parsed
+    pub fn is_end(&self) -> bool {
+        self.peek == Token::EOF
+    }
+
+    /// Skip the `;` between statements, return `false` once the whole input has been parsed
+    pub fn skip_separators(&mut self) -> Result<bool> {
+        while self.peek == Token::Semicolon {
+            self.pop()?;
+        }
+        Ok(!self.is_end())
+    }
+
+    pub fn precedence(&self) -> &HashMap<OpSymbol, i8> {
+        &self.precedence
+    }
+
+    /// Precedence of `token` as binary operator, `-1` if it is not a known binary operator
+    fn tok_precedence(&self, token: &Token) -> i8 {
+        OpSymbol::try_from(token.clone())
+            .ok()
+            .and_then(|op| self.precedence.get(&op).copied())
+            .unwrap_or(-1)
+    }
+
     fn pop(&mut self) -> Result<Token> {
-        Ok(mem::replace(&mut self.peek, self.inner_lex.next()?))
+        let next = self.inner_lex.next()?;
+        self.prev_span = mem::replace(&mut self.peek_span, self.inner_lex.span());
+        Ok(mem::replace(&mut self.peek, next))
+    }
+
+    /// Pop an operator token
+    fn pop_op<Op>(&mut self) -> Result<Op>
+    where
+        Op: TryFrom<Token, Error = ParserError>,
+    {
+        let span = self.span();
+        Op::try_from(self.pop()?).map_err(|e| e.with_span(span).into())
+    }
+
+    /// Span from `start` to the last token popped
+    fn span_from(&self, start: Span) -> Span {
+        start.to(self.prev_span)
+    }
+
+    /// Skip the tokens up to the next statement: `def`, `export`, `extern` or `;`.
+    ///
+    /// Errors of the lexer on the way are pushed to `errors`.
+    fn synchronize(&mut self, errors: &mut Vec<Error>) {
+        while !matches!(
+            self.peek,
+            Token::Def | Token::Export | Token::Extern | Token::Semicolon | Token::EOF
+        ) {
+            if let Err(e) = self.pop() {
+                errors.push(e);
+            }
+        }
+    }
+
+    /// Parse an expression, or push the error to `errors` and replace the expression
+    /// with an [`ExprAst::Error`] up to the next statement
+    fn parse_expr_or_error(&mut self, errors: &mut Vec<Error>) -> ExprAst {
+        let start = self.span();
+        match self.parse_expr() {
+            Ok(expr) => expr,
+            Err(e) => {
+                errors.push(e);
+                self.synchronize(errors);
+                if self.prev_span.end < start.start {
+                    ExprAst::Error(Span {
+                        end: start.start,
+                        ..start
+                    })
+                } else {
+                    ExprAst::Error(self.span_from(start))
+                }
+            }
+        }
     }
 
     /// ```BNF
     /// expression
-    ///     ::= primary binoprhs
+    ///     ::= unary binoprhs
     /// ```
     ///
-    /// - primary => [`Parser::parse_primary`]
+    /// - unary => [`Parser::parse_unary`]
     /// - binoprhs => [`Parser::parse_binop_rhs`]
     pub fn parse_expr(&mut self) -> Result<ExprAst> {
-        let lhs = self.parse_primary()?;
+        let lhs = self.parse_unary()?;
 
         self.parse_binop_rhs(0, lhs)
     }
 
     /// ```BNF
     /// binoprhs
-    ///     ::= ('+' primary)*
+    ///     ::= (op unary)*
     /// ```
     ///
-    /// - primary => [`Parser::parse_primary`]
+    /// - op => any operator in [`Parser::precedence`]
+    /// - unary => [`Parser::parse_unary`]
     pub fn parse_binop_rhs(&mut self, expr_precedence: i8, mut lhs: ExprAst) -> Result<ExprAst> {
         loop {
-            let tok_prec = self.peek().precedence();
+            let tok_prec = self.tok_precedence(self.peek());
             if tok_prec < expr_precedence {
                 return Ok(lhs);
             }
 
-            let op: OpSymbol = self.pop()?.try_into()?;
-            let mut rhs = self.parse_primary()?;
+            let op: OpSymbol = self.pop_op()?;
+            if op == OpSymbol::Assign && !matches!(lhs, ExprAst::Variable(..)) {
+                return ParserError::syn_err("Destination of `=` must be a variable", lhs.span());
+            }
+            let mut rhs = self.parse_unary()?;
 
-            let next_prec = self.peek().precedence();
+            let next_prec = self.tok_precedence(self.peek());
             if tok_prec < next_prec {
                 rhs = self.parse_binop_rhs(tok_prec + 1, rhs)?;
             }
 
-            lhs = ExprAst::Binary(Box::new(BinaryExprAst::new(op, lhs, rhs)));
+            let span = lhs.span().to(rhs.span());
+            lhs = ExprAst::Binary(Box::new(BinaryExprAst::new(op, lhs, rhs)), span);
+        }
+    }
+
+    /// ```BNF
+    /// unary
+    ///     ::= primary
+    ///     ::= unaryop unary
+    /// ```
+    ///
+    /// - unaryop => `-`, `!` or [`Token::Operator`]
+    /// - primary => [`Parser::parse_primary`]
+    /// - unary => [`ExprAst::Unary`]
+    pub fn parse_unary(&mut self) -> Result<ExprAst> {
+        match self.peek() {
+            Token::Minus | Token::Exclamation | Token::Operator(_) => {
+                let start = self.span();
+                let op: UnaryOpSymbol = self.pop_op()?;
+                let operand = self.parse_unary()?;
+
+                Ok(ExprAst::Unary(
+                    Box::new(UnaryExprAst::new(op, operand)),
+                    self.span_from(start),
+                ))
+            }
+            _ => self.parse_primary(),
         }
     }
 
@@ -83,9 +229,12 @@ where
     pub fn parse_number(&mut self) -> Result<ExprAst> {
         let token = self.pop()?;
         if let Token::Number(number) = token {
-            Ok(ExprAst::Number(number))
+            Ok(ExprAst::Number(number, self.prev_span))
         } else {
-            Err(ParserError::SyntaxError(format!("Expects {{Number}}, get `{token:?}`")).into())
+            ParserError::syn_err(
+                format!("Expects {{Number}}, get `{token:?}`"),
+                self.prev_span,
+            )
         }
     }
 
@@ -101,7 +250,7 @@ where
 
         match self.pop()? {
             Token::RightBracket => Ok(expr),
-            _ => Err(ParserError::SyntaxError("Expects `)`".to_string()).into()),
+            _ => ParserError::syn_err("Expects `)`", self.prev_span),
         }
     }
 
@@ -115,6 +264,7 @@ where
     /// - expression => [`Parser::parse_expr`]
     /// - identifierexpr => [`ExprAst::Variable`] or [`ExprAst::Call`]
     pub fn parse_identifier(&mut self) -> Result<ExprAst> {
+        let start = self.span();
         let token = self.pop()?;
         if let Token::Identifier(identifier) = token {
             if let Token::LeftBracket = self.peek() {
@@ -137,92 +287,355 @@ where
                         }
 
                         if *peek != Token::Comma {
-                            return ParserError::syn_err("Expected ')' or ',' in argument list");
+                            return ParserError::syn_err(
+                                "Expected ')' or ',' in argument list",
+                                self.span(),
+                            );
                         }
 
                         self.pop()?;
                     },
                 }
 
-                Ok(ExprAst::Call(Box::new(CallExprAst::new(identifier, args))))
+                Ok(ExprAst::Call(
+                    Box::new(CallExprAst::new(identifier, args)),
+                    self.span_from(start),
+                ))
             } else {
-                Ok(ExprAst::Variable(identifier))
+                Ok(ExprAst::Variable(identifier, start))
             }
         } else {
-            ParserError::syn_err(format!("Expect `identifier`, but get token: {token:?}"))
+            ParserError::syn_err(
+                format!("Expect `identifier`, but get token: {token:?}"),
+                self.prev_span,
+            )
         }
     }
 
+    /// ```BNF
+    /// ifexpr ::= 'if' expression 'then' expression 'else' expression
+    /// ```
+    ///
+    /// - 'if' => [`Token::If`]
+    /// - expression => [`Parser::parse_expr`]
+    /// - ifexpr => [`ExprAst::If`]
+    pub fn parse_if(&mut self) -> Result<ExprAst> {
+        let start = self.span();
+        // eat `if`
+        self.pop()?;
+        let cond = self.parse_expr()?;
+
+        if &Token::Then != self.peek() {
+            return ParserError::syn_err("Expected `then` in if expression", self.span());
+        }
+        self.pop()?;
+        let then = self.parse_expr()?;
+
+        if &Token::Else != self.peek() {
+            return ParserError::syn_err("Expected `else` in if expression", self.span());
+        }
+        self.pop()?;
+        let otherwise = self.parse_expr()?;
+
+        Ok(ExprAst::If(
+            Box::new(IfExprAst::new(cond, then, otherwise)),
+            self.span_from(start),
+        ))
+    }
+
+    /// ```BNF
+    /// forexpr ::= 'for' identifier '=' expression ',' expression (',' expression)? 'in' expression
+    /// ```
+    ///
+    /// - 'for' => [`Token::For`]
+    /// - identifier => [`Token::Identifier`]
+    /// - expression => [`Parser::parse_expr`]
+    /// - forexpr => [`ExprAst::For`]
+    pub fn parse_for(&mut self) -> Result<ExprAst> {
+        let start = self.span();
+        // eat `for`
+        self.pop()?;
+
+        let var = match self.pop()? {
+            Token::Identifier(var) => var,
+            token => {
+                return ParserError::syn_err(
+                    format!("Expected identifier after `for`, but get token: {token:?}"),
+                    self.prev_span,
+                )
+            }
+        };
+
+        if &Token::Assign != self.peek() {
+            return ParserError::syn_err("Expected `=` after for", self.span());
+        }
+        self.pop()?;
+        let init = self.parse_expr()?;
+
+        if &Token::Comma != self.peek() {
+            return ParserError::syn_err("Expected `,` after for start value", self.span());
+        }
+        self.pop()?;
+        let end = self.parse_expr()?;
+
+        let step = if &Token::Comma == self.peek() {
+            self.pop()?;
+            Some(self.parse_expr()?)
+        } else {
+            None
+        };
+
+        if &Token::In != self.peek() {
+            return ParserError::syn_err("Expected `in` after for", self.span());
+        }
+        self.pop()?;
+        let body = self.parse_expr()?;
+
+        Ok(ExprAst::For(
+            Box::new(ForExprAst::new(var, init, end, step, body)),
+            self.span_from(start),
+        ))
+    }
+
+    /// ```BNF
+    /// varexpr ::= 'var' identifier ('=' expression)? (',' identifier ('=' expression)?)* 'in' expression
+    /// ```
+    ///
+    /// - 'var' => [`Token::Var`]
+    /// - identifier => [`Token::Identifier`]
+    /// - expression => [`Parser::parse_expr`]
+    /// - varexpr => [`ExprAst::Var`]
+    pub fn parse_var(&mut self) -> Result<ExprAst> {
+        let start = self.span();
+        // eat `var`
+        self.pop()?;
+
+        let mut vars = Vec::new();
+        loop {
+            let name = match self.pop()? {
+                Token::Identifier(name) => name,
+                token => {
+                    return ParserError::syn_err(
+                        format!("Expected identifier in var, but get token: {token:?}"),
+                        self.prev_span,
+                    )
+                }
+            };
+
+            let init = if &Token::Assign == self.peek() {
+                self.pop()?;
+                Some(self.parse_expr()?)
+            } else {
+                None
+            };
+            vars.push((name, init));
+
+            if &Token::Comma != self.peek() {
+                break;
+            }
+            self.pop()?;
+        }
+
+        if &Token::In != self.peek() {
+            return ParserError::syn_err("Expected `in` after var", self.span());
+        }
+        self.pop()?;
+        let body = self.parse_expr()?;
+
+        Ok(ExprAst::Var(
+            Box::new(VarExprAst::new(vars, body)),
+            self.span_from(start),
+        ))
+    }
+
     /// ```BNF
     /// primary
     ///     ::= identifierexpr
     ///     ::= numberexpr
     ///     ::= parentexpr
+    ///     ::= ifexpr
+    ///     ::= forexpr
+    ///     ::= varexpr
     /// ```
     ///
     /// - identifierexpr => [`Parser::parse_identifier`]
     /// - numberexpr => [`Parser::parse_number`]
     /// - parentexpr => [`Parser::parse_parent`]
+    /// - ifexpr => [`Parser::parse_if`]
+    /// - forexpr => [`Parser::parse_for`]
+    /// - varexpr => [`Parser::parse_var`]
     pub fn parse_primary(&mut self) -> Result<ExprAst> {
         match self.peek() {
             Token::LeftBracket => self.parse_parent(),
             Token::Number(_) => self.parse_number(),
             Token::Identifier(_) => self.parse_identifier(),
-            _ => Err(ParserError::UnexpectedToken(self.peek().clone()).into()),
+            Token::If => self.parse_if(),
+            Token::For => self.parse_for(),
+            Token::Var => self.parse_var(),
+            _ => Err(ParserError::UnexpectedToken(self.peek().clone(), self.span()).into()),
         }
     }
 
     /// ```BNF
     /// prototype
     ///     ::= id '(' id* ')'
+    ///     ::= 'binary' op number? '(' id id ')'
+    ///     ::= 'unary' op '(' id ')'
     /// ```
     ///
     /// - id => [`Token::Identifier`]
+    /// - 'binary' => [`Token::Binary`]
+    /// - 'unary' => [`Token::Unary`]
+    /// - op => [`Token::Operator`]
+    ///
+    /// The precedence of a binary operator (default `30`) is registered as soon as
+    /// its prototype is parsed, so that its body can use it. It is removed again if
+    /// the rest of the definition fails to parse.
     pub fn parse_prototype(&mut self) -> Result<PrototypeAst> {
-        if let Token::Identifier(fn_name) = self.pop()? {
-            if &Token::LeftBracket != self.peek() {
-                return ParserError::syn_err("Expected '(' in prototype");
+        let start = self.span();
+        let mut proto = match self.pop()? {
+            Token::Identifier(fn_name) => {
+                let args = self.parse_prototype_args()?;
+
+                PrototypeAst::new(fn_name, args)
             }
-            // eat '('
-            self.pop()?;
+            Token::Binary => {
+                let op = match self.pop()? {
+                    Token::Operator(op) => op,
+                    token => {
+                        return ParserError::syn_err(
+                            format!("Expected operator after `binary`, but get token: {token:?}"),
+                            self.prev_span,
+                        )
+                    }
+                };
+
+                let precedence = match *self.peek() {
+                    Token::Number(number) => {
+                        self.pop()?;
+                        if !(1.0..=100.0).contains(&number) || number.fract() != 0.0 {
+                            return ParserError::syn_err(
+                                "Invalid precedence: must be an integer in 1..=100",
+                                self.prev_span,
+                            );
+                        }
+                        number as i8
+                    }
+                    _ => DEFAULT_BINARY_PRECEDENCE,
+                };
+
+                let args = self.parse_prototype_args()?;
+                if args.len() != 2 {
+                    return ParserError::syn_err(
+                        "Invalid number of operands for binary operator",
+                        self.span_from(start),
+                    );
+                }
 
-            let mut args = Vec::new();
+                self.precedence.insert(OpSymbol::Custom(op), precedence);
 
-            while let Token::Identifier(_) = self.peek() {
-                if let Token::Identifier(arg_name) = self.pop()? {
-                    args.push(arg_name);
-                }
+                PrototypeAst::new_binary(op, precedence, args)
             }
+            Token::Unary => {
+                let op = match self.pop()? {
+                    Token::Operator(op) => op,
+                    token => {
+                        return ParserError::syn_err(
+                            format!("Expected operator after `unary`, but get token: {token:?}"),
+                            self.prev_span,
+                        )
+                    }
+                };
+
+                let args = self.parse_prototype_args()?;
+                if args.len() != 1 {
+                    return ParserError::syn_err(
+                        "Invalid number of operands for unary operator",
+                        self.span_from(start),
+                    );
+                }
 
-            if &Token::RightBracket != self.peek() {
-                return ParserError::syn_err("Expected ')' in prototype");
+                PrototypeAst::new_unary(op, args)
             }
+            _ => return Err(ParserError::ExpectedFunctionName(self.prev_span).into()),
+        };
+        proto.span = self.span_from(start);
 
-            self.pop()?;
+        Ok(proto)
+    }
 
-            Ok(PrototypeAst {
-                name: fn_name,
-                args,
-            })
-        } else {
-            Err(ParserError::ExpectedFunctionName.into())
+    /// ```BNF
+    /// '(' id* ')'
+    /// ```
+    fn parse_prototype_args(&mut self) -> Result<Vec<String>> {
+        if &Token::LeftBracket != self.peek() {
+            return ParserError::syn_err("Expected '(' in prototype", self.span());
+        }
+        // eat '('
+        self.pop()?;
+
+        let mut args = Vec::new();
+
+        while let Token::Identifier(_) = self.peek() {
+            if let Token::Identifier(arg_name) = self.pop()? {
+                args.push(arg_name);
+            }
+        }
+
+        if &Token::RightBracket != self.peek() {
+            return ParserError::syn_err("Expected ')' in prototype", self.span());
         }
+
+        self.pop()?;
+
+        Ok(args)
     }
 
     /// ```BNF
-    /// definition ::= 'def' prototype expression
+    /// definition ::= 'export'? 'def' prototype expression
     /// ```
     ///
+    /// - 'export' => [`Token::Export`]
     /// - 'def' => [`Token::Def`]
     /// - prototype => [`Parser::parse_prototype`]
     /// - expression => [`Parser::parse_expr`]
     pub fn parse_definition(&mut self) -> Result<FunctionAst> {
+        let start = self.span();
+        let precedence = self.precedence.clone();
+        let function = self.parse_definition_head().and_then(|proto| {
+            let body = self.parse_expr()?;
+
+            Ok(FunctionAst {
+                proto,
+                body,
+                span: self.span_from(start),
+            })
+        });
+
+        if function.is_err() {
+            self.precedence = precedence;
+        }
+        function
+    }
+
+    /// `'export'? 'def' prototype` of a definition
+    fn parse_definition_head(&mut self) -> Result<PrototypeAst> {
+        let exported = self.peek == Token::Export;
+        if exported {
+            self.pop()?;
+            if self.peek != Token::Def {
+                return ParserError::syn_err("Expected 'def' after 'export'", self.span());
+            }
+        }
         self.pop()?; // eat def
 
-        Ok(FunctionAst {
-            proto: self.parse_prototype()?,
-            body: self.parse_expr()?,
-        })
+        let mut proto = self.parse_prototype()?;
+        if exported && proto.kind != PrototypeKind::Function {
+            return ParserError::syn_err("Operators can't be exported", proto.span);
+        }
+        proto.exported = exported;
+
+        Ok(proto)
     }
 
     /// ```BNF
@@ -237,6 +650,93 @@ where
         self.parse_prototype()
     }
 
+    /// ```BNF
+    /// item
+    ///     ::= definition
+    ///     ::= external
+    ///     ::= toplevelexpr
+    /// ```
+    ///
+    /// - definition => [`Parser::parse_definition`]
+    /// - external => [`Parser::parse_extern`]
+    /// - toplevelexpr => [`Parser::parse_toplevel`]
+    pub fn parse_item(&mut self) -> Result<Item> {
+        match self.peek() {
+            Token::Def | Token::Export => Ok(Item::Definition(self.parse_definition()?)),
+            Token::Extern => Ok(Item::Extern(self.parse_extern()?)),
+            _ => Ok(Item::Expression(self.parse_toplevel()?)),
+        }
+    }
+
+    /// ```BNF
+    /// program ::= (item ';'*)*
+    /// ```
+    ///
+    /// - item => [`Parser::parse_item`]
+    ///
+    /// Parse the rest of the input, see [`Parser::parse_all`] to recover from errors.
+    pub fn parse_program(&mut self) -> Result<Vec<Item>> {
+        let mut items = Vec::new();
+        while self.skip_separators()? {
+            items.push(self.parse_item()?);
+        }
+
+        Ok(items)
+    }
+
+    /// Parse the rest of the input and collect every error instead of stopping at the first.
+    ///
+    /// After an error the parser skips to the next `def`, `export`, `extern` or `;`.
+    /// A definition or top-level expression whose body fails to parse is kept with an
+    /// [`ExprAst::Error`] as body, other statements which fail are left out.
+    pub fn parse_all(&mut self) -> (Vec<Item>, Vec<Error>) {
+        let mut items = Vec::new();
+        let mut errors = Vec::new();
+
+        loop {
+            match self.skip_separators() {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(e) => {
+                    errors.push(e);
+                    continue;
+                }
+            }
+
+            let start = self.span();
+            let precedence = self.precedence.clone();
+            let item = match self.peek() {
+                Token::Def | Token::Export => self.parse_definition_head().map(|proto| {
+                    let body = self.parse_expr_or_error(&mut errors);
+                    if matches!(body, ExprAst::Error(_)) {
+                        self.precedence = precedence.clone();
+                    }
+                    Item::Definition(FunctionAst {
+                        proto,
+                        body,
+                        span: self.span_from(start),
+                    })
+                }),
+                Token::Extern => self.parse_extern().map(Item::Extern),
+                _ => {
+                    let body = self.parse_expr_or_error(&mut errors);
+                    Ok(Item::Expression(Self::toplevel(body)))
+                }
+            };
+
+            match item {
+                Ok(item) => items.push(item),
+                Err(e) => {
+                    self.precedence = precedence;
+                    errors.push(e);
+                    self.synchronize(&mut errors);
+                }
+            }
+        }
+
+        (items, errors)
+    }
+
     /// ```BNF
     /// toplevelexpr ::= expression
     /// ```
@@ -245,11 +745,79 @@ where
     pub fn parse_toplevel(&mut self) -> Result<FunctionAst> {
         let expr = self.parse_expr()?;
 
-        let proto = PrototypeAst {
-            name: "".to_string(),
-            args: Vec::new(),
-        };
+        Ok(Self::toplevel(expr))
+    }
 
-        Ok(FunctionAst { proto, body: expr })
+    /// Wrap a top-level expression into an anonymous function
+    fn toplevel(expr: ExprAst) -> FunctionAst {
+        let mut proto = PrototypeAst::new("".to_string(), Vec::new());
+        proto.span = expr.span();
+
+        FunctionAst {
+            proto,
+            span: expr.span(),
+            body: expr,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        ast::{ExprAst, Item, OpSymbol},
+        error::Error,
+        parser::Parser,
+    };
+
+    #[test]
+    fn test_parse_all() {
+        let source = "def f(x) x +\n\
+                      def g(x) x * 2;\n\
+                      extern (x);\n\
+                      g(1, );\n\
+                      f(g(2))";
+        let (items, errors) = Parser::new(source).unwrap().parse_all();
+        assert_eq!(errors.len(), 3);
+
+        // the statements are kept except the broken extern
+        assert_eq!(items.len(), 4);
+        assert!(matches!(&items[0], Item::Definition(f) if matches!(f.body, ExprAst::Error(_))));
+        assert!(matches!(&items[1], Item::Definition(g) if matches!(g.body, ExprAst::Binary(..))));
+        assert!(matches!(&items[2], Item::Expression(e) if matches!(e.body, ExprAst::Error(_))));
+        assert!(matches!(&items[3], Item::Expression(e) if matches!(e.body, ExprAst::Call(..))));
+    }
+
+    #[test]
+    fn test_synchronize() {
+        // at `export`
+        let (items, errors) = Parser::new("def f(x) x + ) export def g(x) x")
+            .unwrap()
+            .parse_all();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(items.len(), 2);
+        assert!(matches!(&items[1], Item::Definition(g) if g.proto.exported));
+
+        // the lexer errors met on the way are reported too, here a control character
+        let (items, errors) = Parser::new("f(1 +) \u{7} 2; 3").unwrap().parse_all();
+        assert_eq!(errors.len(), 2);
+        assert!(matches!(errors[1], Error::LexError(_)));
+        assert_eq!(items.len(), 2);
+        assert!(matches!(&items[1], Item::Expression(e) if matches!(e.body, ExprAst::Number(..))));
+
+        // a body missing altogether is an empty error where it should start
+        let (items, errors) = Parser::new("def f(x)  ; 1").unwrap().parse_all();
+        assert_eq!(errors.len(), 1);
+        let Item::Definition(f) = &items[0] else {
+            panic!("expected a definition");
+        };
+        assert!(matches!(f.body, ExprAst::Error(span) if (span.start, span.end) == (10, 10)));
+
+        // a failed operator definition doesn't keep its precedence,
+        // so what follows reads `1` then the unary `% 2`
+        let mut parser = Parser::new("def binary% 50 (a b) a + ); 1 % 2").unwrap();
+        let (items, errors) = parser.parse_all();
+        assert_eq!((items.len(), errors.len()), (3, 1));
+        assert!(matches!(&items[1], Item::Expression(e) if matches!(e.body, ExprAst::Number(..))));
+        assert_eq!(parser.precedence().get(&OpSymbol::Custom('%')), None);
     }
 }
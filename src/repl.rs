@@ -0,0 +1,473 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Write},
+    mem,
+    time::Instant,
+};
+
+use crate::{
+    ast::{Item, OpSymbol, PrototypeKind},
+    compile::Compiler,
+    diagnostic::Diagnostic,
+    error::Error,
+    parser::{default_precedence, Parser},
+    target::{OptLevel, Target},
+    Result,
+};
+
+/// Name of the input in the diagnostics
+const SOURCE_NAME: &str = "<stdin>";
+
+const HELP: &str = "\
+:help            show this message
+:ir <fn>         print the llvm ir of a function
+:asm <fn>        print the assembly of a function
+:ast <input>     print the syntax tree of the input
+:funcs           list the known functions
+:load <file>     run a source file in the session
+:save <file>     write the definitions of the session to a file
+:reset           start a new session
+:opt <level>     set the optimization level from 0 to 3, or the llvm pass pipeline,
+                 of the next definitions
+:time <expr>     evaluate an expression and print how long compiling and running it took";
+
+/// Interactive session with the jit, fed line by line by [`Repl::feed`].
+///
+/// The values of top-level expressions are printed. An item spread over several lines,
+/// e.g. a `def` whose body is on the next line, is compiled once it is complete.
+pub struct Repl {
+    compiler: Compiler,
+    /// kept across inputs, so that user-defined operators stay usable
+    precedence: HashMap<OpSymbol, i8>,
+    /// lines of the incomplete input
+    buffer: String,
+    /// name and source of the definitions and externs compiled, written by `:save`
+    items: Vec<(String, String)>,
+    /// colour the diagnostics, see [`crate::diagnostic::color_enabled`]
+    pub color: bool,
+}
+
+impl Repl {
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            compiler: Compiler::new()?,
+            precedence: default_precedence(),
+            buffer: String::new(),
+            items: Vec::new(),
+            color: false,
+        })
+    }
+
+    pub fn compiler(&self) -> &Compiler {
+        &self.compiler
+    }
+
+    pub fn compiler_mut(&mut self) -> &mut Compiler {
+        &mut self.compiler
+    }
+
+    /// `> `, or `... ` while the input is incomplete
+    pub fn prompt(&self) -> &'static str {
+        if self.buffer.is_empty() {
+            "> "
+        } else {
+            "... "
+        }
+    }
+
+    /// Feed a line of input, the values and the diagnostics are written to `out`.
+    ///
+    /// Lines are gathered as long as the input ends in the middle of an item, an empty
+    /// line compiles them anyway. An empty line on its own prints the module being built,
+    /// and a line starting with `:` is a command, see `:help`.
+    pub fn feed<W: Write>(&mut self, line: &str, out: &mut W) -> io::Result<()> {
+        let continued = !self.buffer.is_empty();
+        if !continued && line.trim().is_empty() {
+            return writeln!(out, "{}", self.compiler.print_module());
+        }
+        if !continued && line.trim_start().starts_with(':') {
+            return self.command(line.trim(), out);
+        }
+
+        self.buffer.push_str(line);
+        if !self.buffer.ends_with('\n') {
+            self.buffer.push('\n');
+        }
+        let source = mem::take(&mut self.buffer);
+
+        let mut parser = match Parser::with_precedence(source.as_str(), self.precedence.clone()) {
+            Ok(parser) => parser,
+            Err(e) => return self.report(&e, SOURCE_NAME, &source, out),
+        };
+        let (items, errors) = parser.parse_all();
+        let forced = continued && line.trim().is_empty();
+        if !forced && errors.first().is_some_and(|e| e.is_incomplete(&source)) {
+            self.buffer = source;
+            return Ok(());
+        }
+
+        if !errors.is_empty() {
+            for error in &errors {
+                self.report(error, SOURCE_NAME, &source, out)?;
+            }
+            return Ok(());
+        }
+
+        self.run(SOURCE_NAME, &source, &items, out)
+    }
+
+    /// Compile `items` parsed from `source` and print the values of the expressions,
+    /// stops at the first error
+    fn run<W: Write>(
+        &mut self,
+        name: &str,
+        source: &str,
+        items: &[Item],
+        out: &mut W,
+    ) -> io::Result<()> {
+        for item in items {
+            let result = match item {
+                Item::Expression(expr) => self.compiler.eval_toplevel(expr).map(Some),
+                _ => self.compiler.compile_item(item).map(|_| None),
+            };
+            match result {
+                Ok(Some(value)) => writeln!(out, "{value}")?,
+                Ok(None) => self.record(item, source),
+                Err(e) => return self.report(&e, name, source, out),
+            }
+        }
+        Ok(())
+    }
+
+    /// Keep the precedence of a compiled operator for the next inputs, and the source
+    /// of a compiled definition or extern for `:save`. A redefinition replaces the previous
+    /// one and moves to the end, as it may use later definitions.
+    fn record(&mut self, item: &Item, source: &str) {
+        let span = item.span();
+        let (proto, text) = match item {
+            Item::Definition(function) => {
+                (&function.proto, source[span.start..span.end].to_string())
+            }
+            Item::Extern(proto) => (proto, format!("extern {}", &source[span.start..span.end])),
+            Item::Expression(_) => return,
+        };
+
+        if let PrototypeKind::Binary { op, precedence } = proto.kind {
+            self.precedence.insert(OpSymbol::Custom(op), precedence);
+        }
+        let name = &proto.name;
+
+        self.items.retain(|(item, _)| item != name);
+        self.items.push((name.clone(), text));
+    }
+
+    /// Run the command `line`, e.g. `:ir fib`
+    fn command<W: Write>(&mut self, line: &str, out: &mut W) -> io::Result<()> {
+        let (command, arg) = match line[1..].split_once(char::is_whitespace) {
+            Some((command, arg)) => (command, arg.trim()),
+            None => (&line[1..], ""),
+        };
+
+        match command {
+            "help" | "h" => writeln!(out, "{HELP}"),
+            "ir" => match self.compiler.function_ir(arg) {
+                Some(ir) => writeln!(out, "{}", ir.trim_end()),
+                None => writeln!(out, "error: no function `{arg}` is defined"),
+            },
+            "asm" => {
+                let target = match Target::host() {
+                    Ok(target) => target,
+                    Err(e) => return writeln!(out, "error: {e}"),
+                };
+                match self.compiler.function_asm(arg, &target) {
+                    Ok(Some(asm)) => write!(out, "{asm}"),
+                    Ok(None) => writeln!(out, "error: no function `{arg}` is defined"),
+                    Err(e) => writeln!(out, "error: {e}"),
+                }
+            }
+            "ast" => {
+                let items = Parser::with_precedence(arg, self.precedence.clone())
+                    .and_then(|mut parser| parser.parse_program());
+                match items {
+                    Ok(items) => writeln!(out, "{items:#?}"),
+                    Err(e) => self.report(&e, SOURCE_NAME, arg, out),
+                }
+            }
+            "funcs" => {
+                for (proto, defined) in self.compiler.functions() {
+                    let kind = if defined { "def" } else { "extern" };
+                    writeln!(out, "{kind} {}/{}", proto.name, proto.args.len())?;
+                }
+                Ok(())
+            }
+            "load" => {
+                let source = match fs::read_to_string(arg) {
+                    Ok(source) => source,
+                    Err(e) => return writeln!(out, "error: can't read `{arg}`: {e}"),
+                };
+                let mut parser =
+                    match Parser::with_precedence(source.as_str(), self.precedence.clone()) {
+                        Ok(parser) => parser,
+                        Err(e) => return self.report(&e, arg, &source, out),
+                    };
+                let (items, errors) = parser.parse_all();
+                if !errors.is_empty() {
+                    for error in &errors {
+                        self.report(error, arg, &source, out)?;
+                    }
+                    return Ok(());
+                }
+                self.run(arg, &source, &items, out)
+            }
+            "save" => {
+                let mut source = self
+                    .items
+                    .iter()
+                    .map(|(_, text)| text.as_str())
+                    .collect::<Vec<_>>()
+                    .join(";\n");
+                if !source.is_empty() {
+                    source.push_str(";\n");
+                }
+                match fs::write(arg, source) {
+                    Ok(()) => Ok(()),
+                    Err(e) => writeln!(out, "error: can't write `{arg}`: {e}"),
+                }
+            }
+            "reset" => match Repl::new() {
+                Ok(mut repl) => {
+                    // the optimizations chosen for the session stay
+                    let pass_manager = self.compiler.pass_manager();
+                    if let Some(opt_level) = pass_manager.opt_level() {
+                        repl.compiler.set_opt_level(opt_level);
+                    } else if let Err(e) = repl.compiler.set_passes(pass_manager.passes()) {
+                        return writeln!(out, "error: {e}");
+                    }
+                    *self = Repl {
+                        color: self.color,
+                        ..repl
+                    };
+                    Ok(())
+                }
+                Err(e) => writeln!(out, "error: {e}"),
+            },
+            "opt" => {
+                let opt_level = match arg.trim_start_matches(['-', 'O']) {
+                    "0" => OptLevel::None,
+                    "1" => OptLevel::Less,
+                    "2" => OptLevel::Default,
+                    "3" => OptLevel::Aggressive,
+                    "" => return writeln!(out, "{}", self.compiler.pass_manager().passes()),
+                    _ => {
+                        return match self.compiler.set_passes(arg) {
+                            Ok(()) => Ok(()),
+                            Err(e) => writeln!(out, "error: {e}"),
+                        }
+                    }
+                };
+                self.compiler.set_opt_level(opt_level);
+                Ok(())
+            }
+            "time" => {
+                let expr = Parser::with_precedence(arg, self.precedence.clone())
+                    .and_then(|mut parser| parser.parse_toplevel());
+                let start = Instant::now();
+                match expr.and_then(|expr| self.compiler.eval_toplevel(&expr)) {
+                    Ok(value) => writeln!(out, "{value}\ntime: {:?}", start.elapsed()),
+                    Err(e) => self.report(&e, SOURCE_NAME, arg, out),
+                }
+            }
+            _ => writeln!(out, "error: unknown command `:{command}`, see `:help`"),
+        }
+    }
+
+    /// Forget the incomplete input, e.g. on `ctrl-c`
+    pub fn cancel(&mut self) {
+        self.buffer.clear();
+    }
+
+    /// Keywords, commands and names of the known functions which start with `word`
+    pub fn complete(&self, word: &str) -> Vec<String> {
+        let mut candidates = if word.starts_with(':') {
+            HELP.lines()
+                .filter_map(|line| line.split_whitespace().next())
+                .map(String::from)
+                .collect::<Vec<_>>()
+        } else {
+            let keywords = [
+                "def", "extern", "export", "if", "then", "else", "for", "in", "binary", "unary",
+                "var",
+            ];
+            let functions = self.compiler.functions();
+            // operators are not completed
+            let functions = functions
+                .iter()
+                .map(|(proto, _)| proto.name.as_str())
+                .filter(|name| name.chars().all(|c| c.is_alphanumeric() || c == '_'));
+            keywords
+                .into_iter()
+                .chain(functions)
+                .map(String::from)
+                .collect()
+        };
+
+        candidates.retain(|candidate| candidate.starts_with(word));
+        candidates.sort();
+        candidates.dedup();
+        candidates
+    }
+
+    /// End of the input, compile what is left
+    pub fn finish<W: Write>(&mut self, out: &mut W) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            Ok(())
+        } else {
+            self.feed("", out)
+        }
+    }
+
+    fn report<W: Write>(
+        &self,
+        error: &Error,
+        name: &str,
+        source: &str,
+        out: &mut W,
+    ) -> io::Result<()> {
+        let diagnostic = Diagnostic::from(error);
+        write!(out, "{}", diagnostic.render(name, source, self.color))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Repl;
+
+    #[test]
+    fn test_multi_line() {
+        let mut repl = Repl::new().unwrap();
+        let mut feed = |line: &str| {
+            let mut out = Vec::new();
+            repl.feed(line, &mut out).unwrap();
+            (String::from_utf8(out).unwrap(), repl.prompt())
+        };
+
+        // a `def` without body, unbalanced parentheses and a trailing operator
+        assert_eq!(feed("def add(a b)\n"), (String::new(), "... "));
+        assert_eq!(feed("  (a +\n"), (String::new(), "... "));
+        assert_eq!(feed("   b)\n"), (String::new(), "> "));
+        assert_eq!(feed("add(1, 2) *\n"), (String::new(), "... "));
+        assert_eq!(feed("10\n"), ("30\n".to_string(), "> "));
+
+        // an error in the middle is reported at once, with the lines of the item
+        let (out, prompt) = feed("def f(x) ) + x\n");
+        assert!(out.starts_with("error[E0203]"));
+        assert_eq!(prompt, "> ");
+        assert_eq!(feed("def g(x)\n").1, "... ");
+        let (out, prompt) = feed("  y\n");
+        assert!(out.contains("2 |   y\n"));
+        assert_eq!(prompt, "> ");
+
+        // an empty line gives up on the incomplete input
+        assert_eq!(feed("1 +\n").1, "... ");
+        let (out, prompt) = feed("\n");
+        assert!(out.starts_with("error[E0203]"));
+        assert_eq!(prompt, "> ");
+    }
+
+    #[test]
+    fn test_commands() {
+        let mut repl = Repl::new().unwrap();
+        let mut feed = |line: &str| {
+            let mut out = Vec::new();
+            repl.feed(line, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        feed("extern sin(x)\n");
+        feed("def twice(x) x * 2\n");
+        feed("def twice(x) x + x; def binary| 5 (a b) if a then 1 else b\n");
+        assert_eq!(
+            feed(":funcs\n"),
+            "def binary|/2\nextern sin/1\ndef twice/1\n"
+        );
+        assert!(feed(":ir twice\n").starts_with("define double @twice(double %x)"));
+        assert!(feed(":asm twice\n").contains("twice:"));
+        assert!(feed(":ir sin\n").starts_with("error: no function `sin`"));
+        assert!(feed(":ast 1 | 2\n").contains("Binary"));
+        assert_eq!(feed(":time twice(4)\n").lines().next(), Some("8"));
+        assert!(feed(":opt 4\n").starts_with("error"));
+        assert_eq!(feed(":opt 2\n"), "");
+        assert!(feed(":nope\n").starts_with("error: unknown command `:nope`"));
+
+        // the session is written back as source, and loaded into a fresh one
+        let path =
+            std::env::temp_dir().join(format!("kaleidoscope-repl-{}.ks", std::process::id()));
+        let path = path.to_str().unwrap();
+        // `f` is saved after `h`, which its last definition calls
+        feed("def f() 0; def h() 1; def f() h()\n");
+        assert_eq!(feed(&format!(":save {path}\n")), "");
+        assert_eq!(
+            std::fs::read_to_string(path).unwrap(),
+            "extern sin(x);\ndef twice(x) x + x;\ndef binary| 5 (a b) if a then 1 else b;\n\
+             def h() 1;\ndef f() h();\n"
+        );
+        assert_eq!(feed(":opt\n"), "default<O2>\n");
+        assert_eq!(feed(":reset\n"), "");
+        assert_eq!(feed(":funcs\n"), "");
+        assert_eq!(feed(":opt\n"), "default<O2>\n");
+        assert_eq!(feed(&format!(":load {path}\n")), "");
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(feed("twice(0 | 3)\n"), "6\n");
+        assert_eq!(feed("f()\n"), "1\n");
+    }
+
+    #[test]
+    fn test_complete() {
+        let mut repl = Repl::new().unwrap();
+        let mut out = Vec::new();
+        repl.feed(
+            "extern exp(x); def twice(x) x * 2; def binary~ 5 (a b) a\n",
+            &mut out,
+        )
+        .unwrap();
+
+        assert_eq!(repl.complete("t"), ["then", "twice"]);
+        assert_eq!(repl.complete("ex"), ["exp", "export", "extern"]);
+        assert_eq!(repl.complete("bin"), ["binary"]);
+        assert_eq!(repl.complete(":s"), [":save"]);
+    }
+
+    #[test]
+    fn test_failed_operator() {
+        use crate::ast::OpSymbol;
+
+        let mut repl = Repl::new().unwrap();
+        let mut feed = |line: &str| {
+            let mut out = Vec::new();
+            repl.feed(line, &mut out).unwrap();
+            (
+                String::from_utf8(out).unwrap(),
+                repl.precedence.get(&OpSymbol::Custom('%')).copied(),
+            )
+        };
+
+        // neither a body which fails to parse nor one which fails to compile registers it
+        let (out, precedence) = feed("def binary% 50 (a b) a + )\n");
+        assert!(out.starts_with("error[E0203]"));
+        assert_eq!(precedence, None);
+        let (out, precedence) = feed("def binary% 50 (a b) a + c\n");
+        assert!(out.starts_with("error[E0301]"));
+        assert_eq!(precedence, None);
+        // nor when an earlier item fails
+        let (out, precedence) = feed("def f() g(); def binary% 50 (a b) a - b\n");
+        assert!(out.starts_with("error[E0302]"));
+        assert_eq!(precedence, None);
+
+        assert_eq!(
+            feed("def binary% 50 (a b) a - b\n"),
+            (String::new(), Some(50))
+        );
+        assert_eq!(feed("10 % 2 * 3\n").0, "24\n");
+    }
+}
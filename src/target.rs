@@ -0,0 +1,381 @@
+use std::{
+    ffi::{CStr, CString},
+    io::Error as IoError,
+    mem,
+    path::Path,
+    ptr::null_mut,
+};
+
+use llvm_sys::{
+    bit_writer::LLVMWriteBitcodeToFile,
+    core::{
+        LLVMDisposeMemoryBuffer, LLVMDisposeMessage, LLVMGetBufferSize, LLVMGetBufferStart,
+        LLVMPrintModuleToFile, LLVMSetDataLayout, LLVMSetTarget,
+    },
+    prelude::LLVMModuleRef,
+    target::{
+        LLVMCopyStringRepOfTargetData, LLVMDisposeTargetData, LLVM_InitializeAllAsmParsers,
+        LLVM_InitializeAllAsmPrinters, LLVM_InitializeAllTargetInfos, LLVM_InitializeAllTargetMCs,
+        LLVM_InitializeAllTargets,
+    },
+    target_machine::{
+        LLVMCodeGenFileType, LLVMCodeGenOptLevel, LLVMCodeModel, LLVMCreateTargetDataLayout,
+        LLVMCreateTargetMachine, LLVMDisposeTargetMachine, LLVMGetDefaultTargetTriple,
+        LLVMGetHostCPUFeatures, LLVMGetHostCPUName, LLVMGetTargetFromTriple,
+        LLVMNormalizeTargetTriple, LLVMRelocMode, LLVMTargetMachineEmitToFile,
+        LLVMTargetMachineEmitToMemoryBuffer, LLVMTargetMachineRef, LLVMTargetRef,
+    },
+};
+
+use crate::LLVM_SUCCESS;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OptLevel {
+    /// `-O0`
+    None,
+    /// `-O1`
+    Less,
+    /// `-O2`
+    #[default]
+    Default,
+    /// `-O3`
+    Aggressive,
+}
+
+impl From<OptLevel> for LLVMCodeGenOptLevel {
+    fn from(value: OptLevel) -> Self {
+        match value {
+            OptLevel::None => LLVMCodeGenOptLevel::LLVMCodeGenLevelNone,
+            OptLevel::Less => LLVMCodeGenOptLevel::LLVMCodeGenLevelLess,
+            OptLevel::Default => LLVMCodeGenOptLevel::LLVMCodeGenLevelDefault,
+            OptLevel::Aggressive => LLVMCodeGenOptLevel::LLVMCodeGenLevelAggressive,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RelocMode {
+    /// whatever the target prefers
+    #[default]
+    Default,
+    Static,
+    /// position independent code, required by shared libraries
+    Pic,
+    DynamicNoPic,
+}
+
+impl From<RelocMode> for LLVMRelocMode {
+    fn from(value: RelocMode) -> Self {
+        match value {
+            RelocMode::Default => LLVMRelocMode::LLVMRelocDefault,
+            RelocMode::Static => LLVMRelocMode::LLVMRelocStatic,
+            RelocMode::Pic => LLVMRelocMode::LLVMRelocPIC,
+            RelocMode::DynamicNoPic => LLVMRelocMode::LLVMRelocDynamicNoPic,
+        }
+    }
+}
+
+/// What [`Target::write_module`] writes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputKind {
+    /// native object file, `.o`
+    Object,
+    /// native assembly, `.s`
+    Assembly,
+    /// llvm bitcode, `.bc`
+    Bitcode,
+    /// textual llvm ir, `.ll`
+    LlvmIr,
+}
+
+impl OutputKind {
+    /// Guess the output kind from the extension of `path`
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<Self> {
+        match path.as_ref().extension()?.to_str()? {
+            "o" | "obj" => Some(Self::Object),
+            "s" | "asm" => Some(Self::Assembly),
+            "bc" => Some(Self::Bitcode),
+            "ll" => Some(Self::LlvmIr),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputKind::Object => "o",
+            OutputKind::Assembly => "s",
+            OutputKind::Bitcode => "bc",
+            OutputKind::LlvmIr => "ll",
+        }
+    }
+}
+
+/// Options to create a [`Target`], by default the host with its cpu and features
+#[derive(Debug, Clone, Default)]
+pub struct TargetOptions {
+    /// target triple, `None` for the host
+    pub triple: Option<String>,
+    /// `None` for the host cpu, or `generic` when cross compiling
+    pub cpu: Option<String>,
+    /// `None` for the host features, or none when cross compiling
+    pub features: Option<String>,
+    pub opt_level: OptLevel,
+    pub reloc_mode: RelocMode,
+}
+
+impl TargetOptions {
+    pub fn triple<S: Into<String>>(mut self, triple: S) -> Self {
+        self.triple = Some(triple.into());
+        self
+    }
+
+    pub fn cpu<S: Into<String>>(mut self, cpu: S) -> Self {
+        self.cpu = Some(cpu.into());
+        self
+    }
+
+    pub fn features<S: Into<String>>(mut self, features: S) -> Self {
+        self.features = Some(features.into());
+        self
+    }
+
+    pub fn opt_level(mut self, opt_level: OptLevel) -> Self {
+        self.opt_level = opt_level;
+        self
+    }
+
+    pub fn reloc_mode(mut self, reloc_mode: RelocMode) -> Self {
+        self.reloc_mode = reloc_mode;
+        self
+    }
+}
+
+/// A target machine for ahead-of-time compilation
+pub struct Target {
+    machine: LLVMTargetMachineRef,
+    triple: String,
+    reloc_mode: RelocMode,
+}
+
+impl Target {
+    /// The host machine, with default options
+    pub fn host() -> Result<Self, IoError> {
+        Self::create(&TargetOptions::default())
+    }
+
+    pub fn create(options: &TargetOptions) -> Result<Self, IoError> {
+        unsafe {
+            LLVM_InitializeAllTargetInfos();
+            LLVM_InitializeAllTargets();
+            LLVM_InitializeAllTargetMCs();
+            LLVM_InitializeAllAsmPrinters();
+            LLVM_InitializeAllAsmParsers();
+
+            let host_triple = llvm_string(LLVMGetDefaultTargetTriple());
+            let triple = options.triple.as_deref().unwrap_or(&host_triple);
+            let triple = CString::new(triple).map_err(IoError::other)?;
+            let triple = llvm_string(LLVMNormalizeTargetTriple(triple.as_ptr()));
+            let is_host = triple
+                == llvm_string(LLVMNormalizeTargetTriple(
+                    CString::new(host_triple).unwrap().as_ptr(),
+                ));
+
+            let cpu = match &options.cpu {
+                Some(cpu) => cpu.clone(),
+                None if is_host => llvm_string(LLVMGetHostCPUName()),
+                None => "generic".to_string(),
+            };
+            let features = match &options.features {
+                Some(features) => features.clone(),
+                None if is_host => llvm_string(LLVMGetHostCPUFeatures()),
+                None => String::new(),
+            };
+
+            let c_triple = CString::new(triple.as_str()).unwrap();
+            let cpu = CString::new(cpu).map_err(IoError::other)?;
+            let features = CString::new(features).map_err(IoError::other)?;
+
+            let target = get_target(c_triple.as_ptr())?;
+            let machine = LLVMCreateTargetMachine(
+                target,
+                c_triple.as_ptr(),
+                cpu.as_ptr(),
+                features.as_ptr(),
+                options.opt_level.into(),
+                options.reloc_mode.into(),
+                LLVMCodeModel::LLVMCodeModelDefault,
+            );
+
+            if machine.is_null() {
+                return Err(IoError::other(format!(
+                    "can't create target machine for `{triple}`"
+                )));
+            }
+
+            Ok(Self {
+                machine,
+                triple,
+                reloc_mode: options.reloc_mode,
+            })
+        }
+    }
+
+    pub fn triple(&self) -> &str {
+        &self.triple
+    }
+
+    pub fn reloc_mode(&self) -> RelocMode {
+        self.reloc_mode
+    }
+
+    pub(crate) fn machine(&self) -> LLVMTargetMachineRef {
+        self.machine
+    }
+
+    /// Set data layout and target triple of `module` to the target's
+    pub(crate) fn setup_module(&self, module: LLVMModuleRef) {
+        unsafe {
+            let data_layout = LLVMCreateTargetDataLayout(self.machine);
+            let layout = LLVMCopyStringRepOfTargetData(data_layout);
+            LLVMSetDataLayout(module, layout);
+            LLVMDisposeMessage(layout);
+            LLVMDisposeTargetData(data_layout);
+
+            let triple = CString::new(self.triple.as_str()).unwrap();
+            LLVMSetTarget(module, triple.as_ptr());
+        }
+    }
+
+    /// Write `module` to `path` as `kind`
+    pub(crate) fn write_module<P: AsRef<Path>>(
+        &self,
+        module: LLVMModuleRef,
+        path: P,
+        kind: OutputKind,
+    ) -> Result<(), IoError> {
+        let path = path.as_ref();
+        let c_path = CString::new(path.to_string_lossy().as_bytes()).map_err(IoError::other)?;
+
+        unsafe {
+            let mut error = null_mut();
+            let failed = match kind {
+                OutputKind::Object | OutputKind::Assembly => {
+                    let file_type = if kind == OutputKind::Object {
+                        LLVMCodeGenFileType::LLVMObjectFile
+                    } else {
+                        LLVMCodeGenFileType::LLVMAssemblyFile
+                    };
+                    LLVMTargetMachineEmitToFile(
+                        self.machine,
+                        module,
+                        c_path.as_ptr() as *mut _,
+                        file_type,
+                        &mut error,
+                    ) != LLVM_SUCCESS
+                }
+                OutputKind::Bitcode => LLVMWriteBitcodeToFile(module, c_path.as_ptr()) != 0,
+                OutputKind::LlvmIr => {
+                    LLVMPrintModuleToFile(module, c_path.as_ptr(), &mut error) != LLVM_SUCCESS
+                }
+            };
+
+            if !failed {
+                Ok(())
+            } else if error.is_null() {
+                Err(IoError::other(format!("can't write `{}`", path.display())))
+            } else {
+                Err(IoError::other(llvm_string(error)))
+            }
+        }
+    }
+}
+
+impl Target {
+    /// Native assembly of `module`
+    pub(crate) fn emit_assembly(&self, module: LLVMModuleRef) -> Result<String, IoError> {
+        unsafe {
+            let mut error = null_mut();
+            let mut buffer = null_mut();
+            if LLVMTargetMachineEmitToMemoryBuffer(
+                self.machine,
+                module,
+                LLVMCodeGenFileType::LLVMAssemblyFile,
+                &mut error,
+                &mut buffer,
+            ) != LLVM_SUCCESS
+            {
+                return Err(IoError::other(llvm_string(error)));
+            }
+
+            let asm = std::slice::from_raw_parts(
+                LLVMGetBufferStart(buffer) as *const u8,
+                LLVMGetBufferSize(buffer),
+            );
+            let asm = String::from_utf8_lossy(asm).into_owned();
+            LLVMDisposeMemoryBuffer(buffer);
+            Ok(asm)
+        }
+    }
+}
+
+impl Drop for Target {
+    fn drop(&mut self) {
+        unsafe { LLVMDisposeTargetMachine(self.machine) }
+    }
+}
+
+pub(crate) unsafe fn get_target(triple: *const ::libc::c_char) -> Result<LLVMTargetRef, IoError> {
+    let mut target = mem::MaybeUninit::uninit();
+    let mut error = null_mut();
+    if LLVMGetTargetFromTriple(triple, target.as_mut_ptr(), &mut error) != LLVM_SUCCESS {
+        return Err(IoError::other(llvm_string(error)));
+    }
+
+    Ok(target.assume_init())
+}
+
+/// Take a string allocated by llvm
+unsafe fn llvm_string(message: *mut ::libc::c_char) -> String {
+    let string = CStr::from_ptr(message).to_string_lossy().into_owned();
+    LLVMDisposeMessage(message);
+    string
+}
+
+#[cfg(test)]
+mod test {
+    use super::{OutputKind, Target};
+
+    #[test]
+    fn test_write_module() {
+        use crate::ast::*;
+        use crate::compile::Compiler;
+        use crate::parser::*;
+
+        let target = Target::host().unwrap();
+        let mut compiler = Compiler::with_target(&target, "test_write_module").unwrap();
+
+        let mut parser = Parser::new("def square(x) x * x").unwrap();
+        parser
+            .parse_definition()
+            .unwrap()
+            .codegen(&mut compiler)
+            .unwrap();
+
+        let dir = std::env::temp_dir().join(format!("kaleidoscope-target-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for kind in [
+            OutputKind::Object,
+            OutputKind::Assembly,
+            OutputKind::Bitcode,
+            OutputKind::LlvmIr,
+        ] {
+            let path = dir.join(format!("square.{}", kind.extension()));
+            compiler.write_to_file(&target, &path, kind).unwrap();
+            assert_eq!(OutputKind::from_path(&path), Some(kind));
+            assert!(std::fs::metadata(&path).unwrap().len() > 0);
+        }
+        let ir = std::fs::read_to_string(dir.join("square.ll")).unwrap();
+        assert!(ir.contains("define double @square(double %x)"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}